@@ -0,0 +1,265 @@
+use core::alloc::Layout;
+use core::cmp;
+use core::mem::{align_of, size_of};
+use core::ptr::{self, NonNull};
+use linked_list_allocator;
+
+/// Records a block handed out by the inner heap. Nodes come from the inner
+/// heap as well, so its holes are exactly the gaps between blocks and nodes.
+struct Node {
+    start: usize,
+    size: usize,
+    next: *mut Node,
+}
+
+/// A `linked_list_allocator::Heap` that also keeps a list of the blocks in
+/// use, since the inner heap doesn't expose its holes.
+pub struct Heap {
+    inner: linked_list_allocator::Heap,
+    nodes: *mut Node,
+}
+
+// The nodes only ever point into the heap's own region.
+unsafe impl Send for Heap {}
+
+impl Heap {
+    pub unsafe fn new(bottom: usize, size: usize) -> Heap {
+        Heap {
+            inner: linked_list_allocator::Heap::new(bottom, size),
+            nodes: ptr::null_mut(),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.inner.size()
+    }
+
+    pub fn top(&self) -> usize {
+        self.inner.top()
+    }
+
+    pub fn allocate_first_fit(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        let node = self.inner
+            .allocate_first_fit(Layout::new::<Node>())
+            .ok()?
+            .cast::<Node>();
+        match self.inner.allocate_first_fit(layout.clone()) {
+            Ok(block) => unsafe {
+                ptr::write(
+                    node.as_ptr(),
+                    Node {
+                        start: block.as_ptr() as usize,
+                        size: block_size(&layout),
+                        next: self.nodes,
+                    },
+                );
+                self.nodes = node.as_ptr();
+                Some(block)
+            },
+            Err(_) => {
+                unsafe { self.inner.deallocate(node.cast(), Layout::new::<Node>()) };
+                None
+            }
+        }
+    }
+
+    pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        let address = ptr.as_ptr() as usize;
+        let mut link: *mut *mut Node = &mut self.nodes;
+        while !(*link).is_null() && (**link).start != address {
+            link = &mut (**link).next;
+        }
+        let node = *link;
+        assert!(
+            !node.is_null(),
+            "freed block at {:#x} isn't allocated, was it freed twice?",
+            address
+        );
+
+        *link = (*node).next;
+        self.inner.deallocate(
+            NonNull::new_unchecked(node as *mut u8),
+            Layout::new::<Node>(),
+        );
+        self.inner.deallocate(ptr, layout);
+    }
+
+    /// Adds `by` bytes directly above the current top of the heap.
+    pub unsafe fn extend(&mut self, by: usize) {
+        self.inner.extend(by);
+    }
+
+    /// Iterates over the `(address, size)` of every hole, lowest address
+    /// first. Finding each hole walks all blocks in use.
+    pub fn holes(&self) -> Holes {
+        Holes {
+            heap: self,
+            position: self.inner.bottom(),
+        }
+    }
+
+    /// The lowest block or node starting at or above `address`, as
+    /// `(start, end)`.
+    fn next_in_use(&self, address: usize) -> Option<(usize, usize)> {
+        let mut next: Option<(usize, usize)> = None;
+        let mut node = self.nodes;
+        while !node.is_null() {
+            let (block, own) = unsafe {
+                (
+                    ((*node).start, (*node).size),
+                    (node as usize, block_size(&Layout::new::<Node>())),
+                )
+            };
+            for &(start, size) in [block, own].iter() {
+                if start >= address && next.map_or(true, |(next_start, _)| start < next_start) {
+                    next = Some((start, start + size));
+                }
+            }
+            node = unsafe { (*node).next };
+        }
+        next
+    }
+}
+
+pub struct Holes<'a> {
+    heap: &'a Heap,
+    position: usize,
+}
+
+impl<'a> Iterator for Holes<'a> {
+    type Item = (usize, usize);
+
+    fn next(&mut self) -> Option<(usize, usize)> {
+        let top = self.heap.top();
+        while self.position < top {
+            let hole_start = self.position;
+            let hole_end = match self.heap.next_in_use(hole_start) {
+                Some((start, end)) => {
+                    self.position = end;
+                    start
+                }
+                None => {
+                    self.position = top;
+                    top
+                }
+            };
+            if hole_end > hole_start {
+                return Some((hole_start, hole_end - hole_start));
+            }
+        }
+        None
+    }
+}
+
+// linked_list_allocator rounds every block up like this, so it can hold a
+// hole header once it is freed.
+fn block_size(layout: &Layout) -> usize {
+    let size = cmp::max(layout.size(), 2 * size_of::<usize>());
+    let align = align_of::<usize>();
+    (size + align - 1) & !(align - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{block_size, Heap, Node};
+    use core::alloc::Layout;
+    use std::vec::Vec;
+
+    const HEAP_SIZE: usize = 4096;
+
+    #[repr(align(64))]
+    struct Memory([u8; HEAP_SIZE]);
+
+    fn holes(heap: &Heap) -> Vec<(usize, usize)> {
+        heap.holes().collect()
+    }
+
+    fn node_size() -> usize {
+        block_size(&Layout::new::<Node>())
+    }
+
+    #[test]
+    fn empty_heap_is_one_hole() {
+        let mut memory = Memory([0; HEAP_SIZE]);
+        let bottom = memory.0.as_mut_ptr() as usize;
+        let heap = unsafe { Heap::new(bottom, HEAP_SIZE) };
+        assert_eq!(holes(&heap), [(bottom, HEAP_SIZE)]);
+    }
+
+    #[test]
+    fn allocations_are_left_out_of_the_holes() {
+        let mut memory = Memory([0; HEAP_SIZE]);
+        let bottom = memory.0.as_mut_ptr() as usize;
+        let mut heap = unsafe { Heap::new(bottom, HEAP_SIZE) };
+
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let block = heap.allocate_first_fit(layout.clone()).unwrap();
+        let end = block.as_ptr() as usize + block_size(&layout);
+        assert_eq!(holes(&heap), [(end, bottom + HEAP_SIZE - end)]);
+
+        unsafe { heap.deallocate(block, layout) };
+        assert_eq!(holes(&heap), [(bottom, HEAP_SIZE)]);
+    }
+
+    #[test]
+    fn alignment_padding_is_a_hole() {
+        let mut memory = Memory([0; HEAP_SIZE]);
+        let bottom = memory.0.as_mut_ptr() as usize;
+        let mut heap = unsafe { Heap::new(bottom, HEAP_SIZE) };
+
+        // the node of the block comes first, so the block itself has to be
+        // moved up to the next multiple of 64
+        let layout = Layout::from_size_align(64, 64).unwrap();
+        let block = heap.allocate_first_fit(layout.clone()).unwrap();
+        let start = block.as_ptr() as usize;
+        assert_eq!(start, bottom + 64);
+        assert_eq!(
+            holes(&heap),
+            [
+                (bottom + node_size(), 64 - node_size()),
+                (start + 64, bottom + HEAP_SIZE - start - 64),
+            ]
+        );
+
+        unsafe { heap.deallocate(block, layout) };
+        assert_eq!(holes(&heap), [(bottom, HEAP_SIZE)]);
+    }
+
+    #[test]
+    fn exhausted_heap_has_no_holes() {
+        let mut memory = Memory([0; HEAP_SIZE]);
+        let bottom = memory.0.as_mut_ptr() as usize;
+        let mut heap = unsafe { Heap::new(bottom, HEAP_SIZE) };
+
+        let layout = Layout::from_size_align(HEAP_SIZE - node_size(), 8).unwrap();
+        let block = heap.allocate_first_fit(layout.clone()).unwrap();
+        assert!(holes(&heap).is_empty());
+        assert!(heap.allocate_first_fit(Layout::new::<u8>()).is_none());
+        unsafe { heap.deallocate(block, layout) };
+    }
+
+    #[test]
+    fn extend_adds_to_the_last_hole() {
+        let mut memory = Memory([0; HEAP_SIZE]);
+        let bottom = memory.0.as_mut_ptr() as usize;
+        let mut heap = unsafe { Heap::new(bottom, HEAP_SIZE / 2) };
+        unsafe { heap.extend(HEAP_SIZE / 2) };
+        assert_eq!(heap.top(), bottom + HEAP_SIZE);
+        assert_eq!(holes(&heap), [(bottom, HEAP_SIZE)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "freed twice")]
+    fn double_free() {
+        let mut memory = Memory([0; HEAP_SIZE]);
+        let bottom = memory.0.as_mut_ptr() as usize;
+        let mut heap = unsafe { Heap::new(bottom, HEAP_SIZE) };
+
+        let layout = Layout::new::<u64>();
+        let block = heap.allocate_first_fit(layout.clone()).unwrap();
+        unsafe {
+            heap.deallocate(block, layout.clone());
+            heap.deallocate(block, layout);
+        }
+    }
+}
@@ -1,40 +1,261 @@
 #![feature(const_fn)]
 #![feature(allocator_api)]
 #![feature(alloc)]
+#![cfg_attr(feature = "heap_debug", feature(asm))]
 #![no_std]
 #![deny(warnings)]
 
+#[cfg(test)]
+extern crate std;
 extern crate alloc;
 extern crate linked_list_allocator;
 extern crate spin;
 
+use alloc::boxed::Box;
 use core::alloc::{GlobalAlloc, Layout};
-use core::ptr::NonNull;
-use linked_list_allocator::Heap;
-use spin::Mutex;
+use core::cmp;
+use core::ptr::{self, NonNull};
+use hole_list::Heap;
+use slab::Slabs;
+use spin::{Mutex, Once};
+
+pub use slab::SlabStats;
+
+mod hole_list;
+#[cfg(feature = "heap_debug")]
+mod red_zone;
+mod slab;
+
+/// Called with the current top of the heap and the number of bytes needed
+/// when the heap is exhausted. Returns how many bytes directly above the top
+/// were made available, which may be zero.
+pub type GrowHandler = fn(usize, usize) -> usize;
 
 static HEAP: Mutex<Option<Heap>> = Mutex::new(None);
+// Always locked before `HEAP` when both are needed.
+static SLABS: Mutex<Slabs> = Mutex::new(Slabs::new());
+static GROW_HANDLER: Once<GrowHandler> = Once::new();
+static COUNTERS: Mutex<Counters> = Mutex::new(Counters {
+    bytes_in_use: 0,
+    peak_bytes_in_use: 0,
+    allocations: 0,
+    frees: 0,
+});
+
+struct Counters {
+    bytes_in_use: usize,
+    peak_bytes_in_use: usize,
+    allocations: usize,
+    frees: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub heap_size: usize,
+    /// Bytes requested by allocations that haven't been freed yet.
+    pub bytes_in_use: usize,
+    pub peak_bytes_in_use: usize,
+    pub allocations: usize,
+    pub frees: usize,
+    /// Bytes in the hole list, not counting free objects in the slab caches.
+    pub free_bytes: usize,
+    pub largest_free_hole: usize,
+    pub hole_count: usize,
+}
+
+impl HeapStats {
+    /// The percentage of free memory that lies outside the largest hole.
+    pub fn fragmentation(&self) -> usize {
+        if self.free_bytes == 0 {
+            0
+        } else {
+            100 - self.largest_free_hole * 100 / self.free_bytes
+        }
+    }
+}
 
 pub unsafe fn init(offset: usize, size: usize) {
     *HEAP.lock() = Some(Heap::new(offset, size));
 }
 
+pub fn set_grow_handler(handler: GrowHandler) {
+    GROW_HANDLER.call_once(|| handler);
+}
+
+pub fn slab_stats() -> [SlabStats; slab::CACHE_COUNT] {
+    SLABS.lock().stats()
+}
+
+pub fn stats() -> HeapStats {
+    let mut stats = {
+        let counters = COUNTERS.lock();
+        HeapStats {
+            heap_size: 0,
+            bytes_in_use: counters.bytes_in_use,
+            peak_bytes_in_use: counters.peak_bytes_in_use,
+            allocations: counters.allocations,
+            frees: counters.frees,
+            free_bytes: 0,
+            largest_free_hole: 0,
+            hole_count: 0,
+        }
+    };
+
+    if let Some(ref heap) = *HEAP.lock() {
+        stats.heap_size = heap.size();
+        for (_, size) in heap.holes() {
+            stats.free_bytes += size;
+            stats.largest_free_hole = cmp::max(stats.largest_free_hole, size);
+            stats.hole_count += 1;
+        }
+    }
+    stats
+}
+
+/// Calls `f` with the address and size of every hole in the heap, lowest
+/// address first. The heap is locked meanwhile, so `f` must not allocate.
+pub fn walk_holes<F>(mut f: F)
+where
+    F: FnMut(usize, usize),
+{
+    if let Some(ref heap) = *HEAP.lock() {
+        for (address, size) in heap.holes() {
+            f(address, size);
+        }
+    }
+}
+
+/// Allocates `layout` from the hole list, growing the heap if it is exhausted.
+unsafe fn allocate_from_heap(heap: &mut Heap, layout: Layout) -> Option<NonNull<u8>> {
+    if let Some(ptr) = heap.allocate_first_fit(layout.clone()) {
+        return Some(ptr);
+    }
+    // leave room for aligning the allocation within the new space
+    grow(heap, layout.size() + layout.align());
+    heap.allocate_first_fit(layout)
+}
+
+/// Extends `heap` in place by at least `size` bytes, if the grow handler
+/// can provide them.
+unsafe fn grow(heap: &mut Heap, size: usize) {
+    if let Some(handler) = GROW_HANDLER.try() {
+        let added = handler(heap.top(), size);
+        if added > 0 {
+            heap.extend(added);
+        }
+    }
+}
+
+unsafe fn allocate(layout: Layout) -> Option<NonNull<u8>> {
+    let mut slabs = SLABS.lock();
+    if let Some(ref mut heap) = *HEAP.lock() {
+        match slabs.cache_for(&layout) {
+            Some(cache) => {
+                if cache.is_empty() {
+                    let slab = allocate_from_heap(heap, slab::slab_layout())?;
+                    cache.add_slab(slab);
+                }
+                cache.allocate()
+            }
+            None => allocate_from_heap(heap, layout),
+        }
+    } else {
+        panic!("Heap not initialized!");
+    }
+}
+
+unsafe fn deallocate(ptr: NonNull<u8>, layout: Layout) {
+    if let Some(cache) = SLABS.lock().cache_for(&layout) {
+        cache.deallocate(ptr);
+        return;
+    }
+
+    if let Some(ref mut heap) = *HEAP.lock() {
+        heap.deallocate(ptr, layout)
+    } else {
+        panic!("heap not initalized");
+    }
+}
+
+/// The kernel's global allocator. With the `heap_debug` feature every
+/// allocation is surrounded by red zones that are checked when it is freed.
+/// Running out of memory returns a null pointer, which the kernel's
+/// `alloc_error_handler` reports.
 pub struct Allocator;
 
+impl Allocator {
+    /// Like `alloc`, but for callers that can cope with running out of memory,
+    /// so the `alloc_error_handler` is never involved.
+    pub fn try_alloc(&self, layout: Layout) -> Option<NonNull<u8>> {
+        NonNull::new(unsafe { self.alloc(layout) })
+    }
+}
+
+/// Moves `value` onto the heap, handing it back if there isn't enough memory.
+/// Only valid while `Allocator` is the global allocator.
+pub fn try_box<T>(value: T) -> Result<Box<T>, T> {
+    let layout = Layout::new::<T>();
+    if layout.size() == 0 {
+        return Ok(Box::new(value));
+    }
+
+    match Allocator.try_alloc(layout) {
+        Some(ptr) => unsafe {
+            let ptr = ptr.as_ptr() as *mut T;
+            ptr::write(ptr, value);
+            Ok(Box::from_raw(ptr))
+        },
+        None => Err(value),
+    }
+}
+
 unsafe impl GlobalAlloc for Allocator {
+    #[cfg(not(feature = "heap_debug"))]
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        if let Some(ref mut heap) = *HEAP.lock() {
-            heap.allocate_first_fit(layout).unwrap().as_ptr()
-        } else {
-            panic!("Heap not initialized!");
+        let size = layout.size();
+        match allocate(layout) {
+            Some(ptr) => {
+                record_allocation(size);
+                ptr.as_ptr()
+            }
+            None => ptr::null_mut(),
         }
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        if let Some(ref mut heap) = *HEAP.lock() {
-            heap.deallocate(NonNull::new(ptr).unwrap(), layout)
-        } else {
-            panic!("heap not initalized");
+    #[cfg(feature = "heap_debug")]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match allocate(red_zone::outer_layout(&layout)) {
+            Some(block) => {
+                record_allocation(layout.size());
+                red_zone::arm(block, &layout).as_ptr()
+            }
+            None => ptr::null_mut(),
         }
     }
+
+    #[cfg(not(feature = "heap_debug"))]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        record_free(layout.size());
+        deallocate(NonNull::new(ptr).unwrap(), layout)
+    }
+
+    #[cfg(feature = "heap_debug")]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let block = red_zone::disarm(NonNull::new(ptr).unwrap(), &layout);
+        record_free(layout.size());
+        deallocate(block, red_zone::outer_layout(&layout))
+    }
+}
+
+fn record_allocation(size: usize) {
+    let mut counters = COUNTERS.lock();
+    counters.bytes_in_use += size;
+    counters.peak_bytes_in_use = cmp::max(counters.peak_bytes_in_use, counters.bytes_in_use);
+    counters.allocations += 1;
+}
+
+fn record_free(size: usize) {
+    let mut counters = COUNTERS.lock();
+    counters.bytes_in_use -= size;
+    counters.frees += 1;
 }
@@ -0,0 +1,121 @@
+use core::alloc::Layout;
+use core::mem::size_of;
+use core::ptr::{self, NonNull};
+
+const RED_ZONE_SIZE: usize = 16;
+const CANARY: u8 = 0xfd;
+const POISON: u8 = 0xdd;
+const ALLOCATED: usize = 0xa110_c8ed_a110_c8ed;
+const FREED: usize = 0xf4ee_d0ff_f4ee_d0ff;
+const CALLER_DEPTH: usize = 4;
+
+// Sits directly in front of the front red zone of every allocation.
+#[repr(C)]
+struct Header {
+    // reused by the hole list or a slab cache once the block is freed, so the
+    // rest of the header survives for double free detection
+    _free_list_link: [usize; 2],
+    size: usize,
+    state: usize,
+    // return addresses of whoever last allocated or freed the block
+    callers: [usize; CALLER_DEPTH],
+}
+
+fn front_size(layout: &Layout) -> usize {
+    let size = size_of::<Header>() + RED_ZONE_SIZE;
+    (size + layout.align() - 1) & !(layout.align() - 1)
+}
+
+unsafe fn header(user: *mut u8) -> *mut Header {
+    user.offset(-((RED_ZONE_SIZE + size_of::<Header>()) as isize)) as *mut Header
+}
+
+/// The layout to allocate from the heap so `layout` fits between red zones.
+pub fn outer_layout(layout: &Layout) -> Layout {
+    let size = front_size(layout) + layout.size() + RED_ZONE_SIZE;
+    Layout::from_size_align(size, layout.align()).unwrap()
+}
+
+/// Fills in the header and red zones of a block allocated with
+/// `outer_layout`, returning the part of it handed out to the caller.
+pub unsafe fn arm(block: NonNull<u8>, layout: &Layout) -> NonNull<u8> {
+    let user = block.as_ptr().offset(front_size(layout) as isize);
+
+    let header = header(user);
+    (*header).size = layout.size();
+    (*header).state = ALLOCATED;
+    (*header).callers = callers();
+
+    ptr::write_bytes(user.offset(-(RED_ZONE_SIZE as isize)), CANARY, RED_ZONE_SIZE);
+    ptr::write_bytes(user.offset(layout.size() as isize), CANARY, RED_ZONE_SIZE);
+
+    NonNull::new_unchecked(user)
+}
+
+/// Checks the header and red zones of a block being freed and poisons it,
+/// returning the block to give back to the heap.
+pub unsafe fn disarm(user: NonNull<u8>, layout: &Layout) -> NonNull<u8> {
+    let user = user.as_ptr();
+    let header = header(user);
+
+    match (*header).state {
+        ALLOCATED => {}
+        FREED => report(user, layout, "double free"),
+        _ => report(user, layout, "header overwritten or not a heap pointer"),
+    }
+    if (*header).size != layout.size() {
+        report(user, layout, "freed with a different layout");
+    }
+    if !is_filled(user.offset(-(RED_ZONE_SIZE as isize)), CANARY) {
+        report(user, layout, "front red zone overwritten");
+    }
+    if !is_filled(user.offset(layout.size() as isize), CANARY) {
+        report(user, layout, "back red zone overwritten");
+    }
+
+    ptr::write_bytes(
+        user.offset(-(RED_ZONE_SIZE as isize)),
+        POISON,
+        RED_ZONE_SIZE + layout.size() + RED_ZONE_SIZE,
+    );
+    (*header).state = FREED;
+    (*header).callers = callers();
+
+    NonNull::new_unchecked(user.offset(-(front_size(layout) as isize)))
+}
+
+unsafe fn is_filled(red_zone: *const u8, value: u8) -> bool {
+    (0..RED_ZONE_SIZE).all(|i| *red_zone.offset(i as isize) == value)
+}
+
+unsafe fn report(user: *mut u8, layout: &Layout, problem: &str) -> ! {
+    panic!(
+        "heap corruption: {} at {:#x} ({:?}), last allocated or freed from {:#x?}, now freed from {:#x?}",
+        problem,
+        user as usize,
+        layout,
+        (*header(user)).callers,
+        callers()
+    );
+}
+
+/// Walks the frame pointer chain to collect the return addresses of the
+/// innermost callers. Relies on the kernel keeping frame pointers.
+#[inline(always)]
+fn callers() -> [usize; CALLER_DEPTH] {
+    let mut callers = [0; CALLER_DEPTH];
+    let mut frame_pointer: usize;
+    unsafe {
+        asm!("mov $0, rbp" : "=r"(frame_pointer) ::: "intel", "volatile");
+    }
+    for caller in callers.iter_mut() {
+        if frame_pointer == 0 || frame_pointer % 8 != 0 {
+            break;
+        }
+        unsafe {
+            *caller = *((frame_pointer + 8) as *const usize);
+            frame_pointer = *(frame_pointer as *const usize);
+        }
+    }
+    callers
+}
@@ -0,0 +1,211 @@
+use core::alloc::Layout;
+use core::ptr::NonNull;
+
+pub const SLAB_SIZE: usize = 4096;
+// Caches of 16 to 2048 byte objects, doubling in size.
+pub const CACHE_COUNT: usize = 8;
+
+/// Slabs are aligned to their size, so every object in one is aligned to the
+/// object size.
+pub fn slab_layout() -> Layout {
+    Layout::from_size_align(SLAB_SIZE, SLAB_SIZE).unwrap()
+}
+
+struct FreeObject {
+    next: Option<NonNull<FreeObject>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SlabStats {
+    pub object_size: usize,
+    pub slabs: usize,
+    pub objects_in_use: usize,
+    pub free_objects: usize,
+    pub allocations: usize,
+    pub frees: usize,
+}
+
+/// A cache of equally sized objects carved out of slabs. Free objects are
+/// kept in an intrusive list, so allocating and freeing take constant time.
+/// Slabs are never handed back to the heap.
+pub struct SlabCache {
+    free_list: Option<NonNull<FreeObject>>,
+    stats: SlabStats,
+}
+
+// The free list only points into slabs owned by the cache.
+unsafe impl Send for SlabCache {}
+
+impl SlabCache {
+    const fn new(object_size: usize) -> SlabCache {
+        SlabCache {
+            free_list: None,
+            stats: SlabStats {
+                object_size: object_size,
+                slabs: 0,
+                objects_in_use: 0,
+                free_objects: 0,
+                allocations: 0,
+                frees: 0,
+            },
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.free_list.is_none()
+    }
+
+    /// Splits a new slab, allocated with `slab_layout`, into free objects.
+    pub unsafe fn add_slab(&mut self, slab: NonNull<u8>) {
+        let object_size = self.stats.object_size;
+        for i in (0..SLAB_SIZE / object_size).rev() {
+            let object = NonNull::new_unchecked(slab.as_ptr().offset((i * object_size) as isize));
+            self.push(object);
+        }
+        self.stats.slabs += 1;
+    }
+
+    pub fn allocate(&mut self) -> Option<NonNull<u8>> {
+        let object = match self.free_list {
+            Some(object) => object,
+            None => return None,
+        };
+        self.free_list = unsafe { object.as_ref().next };
+        self.stats.free_objects -= 1;
+        self.stats.objects_in_use += 1;
+        self.stats.allocations += 1;
+        Some(object.cast())
+    }
+
+    pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>) {
+        self.push(ptr);
+        self.stats.objects_in_use -= 1;
+        self.stats.frees += 1;
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        self.stats
+    }
+
+    unsafe fn push(&mut self, ptr: NonNull<u8>) {
+        let object = ptr.cast::<FreeObject>();
+        *object.as_ptr() = FreeObject {
+            next: self.free_list,
+        };
+        self.free_list = Some(object);
+        self.stats.free_objects += 1;
+    }
+}
+
+pub struct Slabs {
+    caches: [SlabCache; CACHE_COUNT],
+}
+
+impl Slabs {
+    pub const fn new() -> Slabs {
+        Slabs {
+            caches: [
+                SlabCache::new(16),
+                SlabCache::new(32),
+                SlabCache::new(64),
+                SlabCache::new(128),
+                SlabCache::new(256),
+                SlabCache::new(512),
+                SlabCache::new(1024),
+                SlabCache::new(2048),
+            ],
+        }
+    }
+
+    /// The cache serving `layout`, or `None` if it is too large for any of
+    /// them.
+    pub fn cache_for(&mut self, layout: &Layout) -> Option<&mut SlabCache> {
+        let size = if layout.size() > layout.align() {
+            layout.size()
+        } else {
+            layout.align()
+        };
+        self.caches
+            .iter_mut()
+            .find(|cache| cache.stats.object_size >= size)
+    }
+
+    pub fn stats(&self) -> [SlabStats; CACHE_COUNT] {
+        let mut stats = [self.caches[0].stats(); CACHE_COUNT];
+        for (stat, cache) in stats.iter_mut().zip(self.caches.iter()) {
+            *stat = cache.stats();
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{slab_layout, Slabs, SLAB_SIZE};
+    use core::alloc::Layout;
+    use core::ptr::NonNull;
+    use std::alloc::{alloc, dealloc};
+
+    fn object_size(slabs: &mut Slabs, size: usize, align: usize) -> Option<usize> {
+        let layout = Layout::from_size_align(size, align).unwrap();
+        slabs
+            .cache_for(&layout)
+            .map(|cache| cache.stats().object_size)
+    }
+
+    #[test]
+    fn size_classes() {
+        let mut slabs = Slabs::new();
+        assert_eq!(object_size(&mut slabs, 1, 1), Some(16));
+        assert_eq!(object_size(&mut slabs, 16, 8), Some(16));
+        assert_eq!(object_size(&mut slabs, 17, 8), Some(32));
+        assert_eq!(object_size(&mut slabs, 100, 4), Some(128));
+        assert_eq!(object_size(&mut slabs, 2048, 8), Some(2048));
+        assert_eq!(object_size(&mut slabs, 2049, 8), None);
+    }
+
+    #[test]
+    fn alignment_picks_the_size_class() {
+        let mut slabs = Slabs::new();
+        assert_eq!(object_size(&mut slabs, 8, 64), Some(64));
+        assert_eq!(object_size(&mut slabs, 8, 2048), Some(2048));
+        assert_eq!(object_size(&mut slabs, 8, 4096), None);
+    }
+
+    #[test]
+    fn objects_come_from_the_slab() {
+        let mut slabs = Slabs::new();
+        let layout = Layout::from_size_align(1024, 1024).unwrap();
+        let cache = slabs.cache_for(&layout).unwrap();
+        assert!(cache.is_empty());
+
+        unsafe {
+            let slab = alloc(slab_layout());
+            cache.add_slab(NonNull::new(slab).unwrap());
+
+            let mut objects = [0; SLAB_SIZE / 1024];
+            for object in objects.iter_mut() {
+                *object = cache.allocate().unwrap().as_ptr() as usize;
+            }
+            assert!(cache.allocate().is_none());
+            for (i, &object) in objects.iter().enumerate() {
+                assert!(object >= slab as usize && object < slab as usize + SLAB_SIZE);
+                assert_eq!(object % 1024, 0);
+                assert!(!objects[..i].contains(&object));
+            }
+
+            // the most recently freed object is handed out first
+            cache.deallocate(NonNull::new(objects[1] as *mut u8).unwrap());
+            assert_eq!(cache.allocate().unwrap().as_ptr() as usize, objects[1]);
+
+            let stats = cache.stats();
+            assert_eq!(stats.slabs, 1);
+            assert_eq!(stats.objects_in_use, 4);
+            assert_eq!(stats.free_objects, 0);
+            assert_eq!(stats.allocations, 5);
+            assert_eq!(stats.frees, 1);
+
+            dealloc(slab, slab_layout());
+        }
+    }
+}
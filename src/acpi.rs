@@ -0,0 +1,237 @@
+use core::slice;
+use memory::{CacheMode, MemoryController, MmioRegion};
+
+const RSDP_SIGNATURE: &[u8] = b"RSD PTR ";
+const MADT_SIGNATURE: &[u8] = b"APIC";
+// The word of the BIOS data area that holds the segment of the extended BIOS
+// data area.
+const EBDA_SEGMENT_POINTER: usize = 0x40e;
+// The RSDP is either in the first KiB of the extended BIOS data area or in
+// this read-only BIOS area, always 16 byte aligned.
+const BIOS_AREA_START: usize = 0xe0000;
+const BIOS_AREA_END: usize = 0x100000;
+const SDT_HEADER_SIZE: usize = 36;
+
+// Types of the entries that follow the fixed part of the MADT.
+const MADT_IO_APIC: u8 = 1;
+const MADT_INTERRUPT_OVERRIDE: u8 = 2;
+const MADT_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+
+// More are ignored.
+pub const MAX_IO_APICS: usize = 4;
+pub const ISA_IRQ_COUNT: usize = 16;
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicInfo {
+    pub id: u8,
+    pub address: usize,
+    /// The first global system interrupt the I/O APIC handles.
+    pub gsi_base: u32,
+}
+
+/// How an ISA IRQ reaches the I/O APICs. Unless the MADT overrides it, the
+/// IRQ is the global system interrupt with the same number, edge triggered
+/// and active high.
+#[derive(Debug, Clone, Copy)]
+pub struct IsaIrq {
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+/// What the multiple APIC description table says about the interrupt
+/// controllers.
+#[derive(Debug)]
+pub struct Madt {
+    pub local_apic_address: usize,
+    /// Whether the machine also has the legacy 8259 PICs.
+    pub has_8259: bool,
+    pub io_apics: [Option<IoApicInfo>; MAX_IO_APICS],
+    pub isa_irqs: [IsaIrq; ISA_IRQ_COUNT],
+}
+
+/// Finds and parses the MADT.
+pub fn find_madt(memory_controller: &mut MemoryController) -> Option<Madt> {
+    let madt = find_table(memory_controller, MADT_SIGNATURE)?;
+    let madt = madt.bytes();
+    if madt.len() < SDT_HEADER_SIZE + 8 {
+        return None;
+    }
+
+    let mut isa_irqs = [IsaIrq {
+        gsi: 0,
+        active_low: false,
+        level_triggered: false,
+    }; ISA_IRQ_COUNT];
+    for (irq, isa_irq) in isa_irqs.iter_mut().enumerate() {
+        isa_irq.gsi = irq as u32;
+    }
+    let mut info = Madt {
+        local_apic_address: u32_at(madt, SDT_HEADER_SIZE) as usize,
+        has_8259: u32_at(madt, SDT_HEADER_SIZE + 4) & 1 != 0,
+        io_apics: [None; MAX_IO_APICS],
+        isa_irqs: isa_irqs,
+    };
+
+    let mut offset = SDT_HEADER_SIZE + 8;
+    while offset + 2 <= madt.len() {
+        let length = madt[offset + 1] as usize;
+        if length < 2 || offset + length > madt.len() {
+            break;
+        }
+        let entry = &madt[offset..offset + length];
+        match entry[0] {
+            MADT_IO_APIC if length >= 12 => {
+                if let Some(slot) = info.io_apics.iter_mut().find(|slot| slot.is_none()) {
+                    *slot = Some(IoApicInfo {
+                        id: entry[2],
+                        address: u32_at(entry, 4) as usize,
+                        gsi_base: u32_at(entry, 8),
+                    });
+                }
+            }
+            // only overrides for the ISA bus exist
+            MADT_INTERRUPT_OVERRIDE if length >= 10 && (entry[3] as usize) < ISA_IRQ_COUNT => {
+                let flags = u16_at(entry, 8);
+                info.isa_irqs[entry[3] as usize] = IsaIrq {
+                    gsi: u32_at(entry, 4),
+                    // 0b00 means the default of the bus
+                    active_low: flags & 0b11 == 0b11,
+                    level_triggered: (flags >> 2) & 0b11 == 0b11,
+                };
+            }
+            MADT_LOCAL_APIC_ADDRESS_OVERRIDE if length >= 12 => {
+                info.local_apic_address = u64_at(entry, 4) as usize;
+            }
+            _ => {}
+        }
+        offset += length;
+    }
+    Some(info)
+}
+
+/// The physical address of the root system description pointer.
+fn find_rsdp(memory_controller: &MemoryController) -> Option<usize> {
+    let ebda_segment = physical_bytes(memory_controller, EBDA_SEGMENT_POINTER, 2)
+        .map(|bytes| u16_at(bytes, 0) as usize)
+        .unwrap_or(0);
+    let ebda_start = ebda_segment << 4;
+    let ebda_length = if ebda_start == 0 { 0 } else { 1024 };
+    let ebda_candidates = (0..ebda_length / 16).map(|i| ebda_start + i * 16);
+    let bios_candidates = (0..(BIOS_AREA_END - BIOS_AREA_START) / 16)
+        .map(|i| BIOS_AREA_START + i * 16);
+
+    ebda_candidates.chain(bios_candidates).find(|&address| {
+        match physical_bytes(memory_controller, address, 20) {
+            Some(rsdp) => &rsdp[..8] == RSDP_SIGNATURE && has_valid_checksum(rsdp),
+            None => false,
+        }
+    })
+}
+
+/// A system description table, header included. The firmware usually keeps
+/// them in reserved memory outside the direct map, so each one is mapped for
+/// as long as it is needed. Uncached, like the holes of the direct map they
+/// might also be in.
+struct Table {
+    region: MmioRegion,
+}
+
+impl Table {
+    fn bytes(&self) -> &[u8] {
+        let address = self.region.virtual_address() as *const u8;
+        unsafe { slice::from_raw_parts(address, self.region.size()) }
+    }
+}
+
+/// The system description table with `signature`.
+fn find_table(memory_controller: &mut MemoryController, signature: &[u8]) -> Option<Table> {
+    let (root_address, pointer_size) = {
+        let rsdp_address = find_rsdp(memory_controller)?;
+        let rsdp = physical_bytes(memory_controller, rsdp_address, 36)?;
+
+        // ACPI 2.0 added the XSDT, which has 64 bit pointers
+        let xsdt_address = if rsdp[15] >= 2 {
+            u64_at(rsdp, 24) as usize
+        } else {
+            0
+        };
+        if xsdt_address != 0 {
+            (xsdt_address, 8)
+        } else {
+            (u32_at(rsdp, 16) as usize, 4)
+        }
+    };
+    let root = table(memory_controller, root_address)?;
+    let root = root.bytes();
+
+    let table_count = (root.len() - SDT_HEADER_SIZE) / pointer_size;
+    for i in 0..table_count {
+        let offset = SDT_HEADER_SIZE + i * pointer_size;
+        let address = if pointer_size == 8 {
+            u64_at(root, offset) as usize
+        } else {
+            u32_at(root, offset) as usize
+        };
+        // the others are unmapped again right away
+        if let Some(table) = table(memory_controller, address) {
+            if &table.bytes()[..4] == signature {
+                return Some(table);
+            }
+        }
+    }
+    None
+}
+
+/// Maps the system description table at `address`, if its checksum is valid.
+fn table(memory_controller: &mut MemoryController, address: usize) -> Option<Table> {
+    // the length is part of the header, which has to be mapped first
+    let length = {
+        let region = memory_controller.map_mmio(address, SDT_HEADER_SIZE, CacheMode::Uncached)?;
+        let header = Table { region: region };
+        u32_at(header.bytes(), 4) as usize
+    };
+    if length < SDT_HEADER_SIZE {
+        return None;
+    }
+    let region = memory_controller.map_mmio(address, length, CacheMode::Uncached)?;
+    let table = Table { region: region };
+    if has_valid_checksum(table.bytes()) {
+        Some(table)
+    } else {
+        None
+    }
+}
+
+/// The bytes at `address` in the direct map of physical memory, which covers
+/// the BIOS areas the RSDP is searched in.
+fn physical_bytes(
+    memory_controller: &MemoryController,
+    address: usize,
+    size: usize,
+) -> Option<&[u8]> {
+    if !memory_controller.is_directly_mapped(address, size) {
+        return None;
+    }
+    let virtual_address = memory_controller.phys_to_virt(address);
+    Some(unsafe { slice::from_raw_parts(virtual_address as *const u8, size) })
+}
+
+/// All bytes of ACPI structures add up to zero.
+fn has_valid_checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+// ACPI structures are little endian and not necessarily aligned.
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from(bytes[offset]) | u16::from(bytes[offset + 1]) << 8
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from(u16_at(bytes, offset)) | u32::from(u16_at(bytes, offset + 2)) << 16
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from(u32_at(bytes, offset)) | u64::from(u32_at(bytes, offset + 4)) << 32
+}
@@ -0,0 +1,247 @@
+use acpi::{IoApicInfo, IsaIrq, Madt, ISA_IRQ_COUNT, MAX_IO_APICS};
+use memory::{CacheMode, MemoryController, MmioRegion};
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// Registers of the local APIC, as offsets into its MMIO region.
+const LOCAL_APIC_ID: usize = 0x20;
+const LOCAL_APIC_TASK_PRIORITY: usize = 0x80;
+const LOCAL_APIC_END_OF_INTERRUPT: usize = 0xb0;
+const LOCAL_APIC_SPURIOUS_VECTOR: usize = 0xf0;
+const LOCAL_APIC_LVT_TIMER: usize = 0x320;
+const LOCAL_APIC_LVT_ERROR: usize = 0x370;
+const LOCAL_APIC_SIZE: usize = 0x400;
+
+const LOCAL_APIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+
+/// The local APIC raises this vector for spurious interrupts. They must not
+/// be acknowledged.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+// The I/O APIC is accessed indirectly, by writing the number of a register to
+// the select register and then accessing the window.
+const IO_APIC_SELECT: usize = 0x00;
+const IO_APIC_WINDOW: usize = 0x10;
+const IO_APIC_SIZE: usize = 0x20;
+
+const IO_APIC_VERSION: u32 = 0x01;
+// Each redirection entry takes two registers, the low half first.
+const IO_APIC_REDIRECTION_TABLE: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+/// Whether the CPU has a local APIC.
+pub fn is_supported() -> bool {
+    use core::arch::x86_64::__cpuid;
+
+    unsafe { __cpuid(1).edx & (1 << 9) != 0 }
+}
+
+/// The local APIC of this CPU together with the I/O APICs, which deliver the
+/// ISA IRQs in place of the 8259 PICs.
+pub struct Apic {
+    local_apic: LocalApic,
+    io_apics: [Option<IoApic>; MAX_IO_APICS],
+    isa_irqs: [IsaIrq; ISA_IRQ_COUNT],
+    // one bit per ISA IRQ that has a redirection entry
+    routed_irqs: u16,
+}
+
+impl Apic {
+    /// Enables the local APIC and routes every ISA IRQ to vector
+    /// `vector_offset + irq` of this CPU. All IRQs start out masked.
+    pub fn new(memory_controller: &mut MemoryController, madt: &Madt, vector_offset: u8) -> Apic {
+        let local_apic = LocalApic::new(memory_controller, madt.local_apic_address);
+        let mut apic = Apic {
+            local_apic: local_apic,
+            io_apics: [None, None, None, None],
+            isa_irqs: madt.isa_irqs,
+            routed_irqs: 0,
+        };
+        for (slot, info) in apic.io_apics.iter_mut().zip(madt.io_apics.iter()) {
+            *slot = info.map(|info| IoApic::new(memory_controller, &info));
+        }
+
+        let destination = u64::from(apic.local_apic.id()) << 56;
+        for irq in 0..ISA_IRQ_COUNT {
+            let isa_irq = apic.isa_irqs[irq];
+            // e.g. the timer usually takes over the GSI of IRQ 2, which is
+            // only the cascade of the 8259s
+            let taken_over = (0..ISA_IRQ_COUNT).any(|other| {
+                other != irq
+                    && apic.isa_irqs[other].gsi == isa_irq.gsi
+                    && apic.isa_irqs[other].gsi != other as u32
+            });
+            if taken_over {
+                continue;
+            }
+
+            let mut entry = destination | REDIRECTION_MASKED | u64::from(vector_offset + irq as u8);
+            if isa_irq.active_low {
+                entry |= REDIRECTION_ACTIVE_LOW;
+            }
+            if isa_irq.level_triggered {
+                entry |= REDIRECTION_LEVEL_TRIGGERED;
+            }
+            match apic.io_apic_mut(isa_irq.gsi) {
+                Some(io_apic) => io_apic.set_redirection(isa_irq.gsi, entry),
+                None => continue,
+            }
+            apic.routed_irqs |= 1 << irq;
+        }
+        apic
+    }
+
+    /// Acknowledges the interrupt that is being handled.
+    pub fn end_of_interrupt(&mut self) {
+        self.local_apic.end_of_interrupt();
+    }
+
+    /// Stops the ISA `irq` from raising interrupts.
+    pub fn mask(&mut self, irq: u8) {
+        self.set_masked(irq, true);
+    }
+
+    /// Lets the ISA `irq` raise interrupts again.
+    pub fn unmask(&mut self, irq: u8) {
+        self.set_masked(irq, false);
+    }
+
+    /// The masks of the ISA IRQs, IRQ 0 in the lowest bit. IRQs without a
+    /// redirection entry read as masked.
+    pub fn masks(&mut self) -> u16 {
+        let mut masks = !self.routed_irqs;
+        for irq in 0..ISA_IRQ_COUNT {
+            if self.routed_irqs & (1 << irq) == 0 {
+                continue;
+            }
+            let gsi = self.isa_irqs[irq].gsi;
+            let masked = match self.io_apic_mut(gsi) {
+                Some(io_apic) => io_apic.redirection(gsi) & REDIRECTION_MASKED != 0,
+                None => true,
+            };
+            if masked {
+                masks |= 1 << irq;
+            }
+        }
+        masks
+    }
+
+    fn set_masked(&mut self, irq: u8, masked: bool) {
+        if irq as usize >= ISA_IRQ_COUNT || self.routed_irqs & (1 << irq) == 0 {
+            return;
+        }
+        let gsi = self.isa_irqs[irq as usize].gsi;
+        if let Some(io_apic) = self.io_apic_mut(gsi) {
+            let entry = io_apic.redirection(gsi);
+            if masked {
+                io_apic.set_redirection(gsi, entry | REDIRECTION_MASKED);
+            } else {
+                io_apic.set_redirection(gsi, entry & !REDIRECTION_MASKED);
+            }
+        }
+    }
+
+    /// The I/O APIC that handles the global system interrupt `gsi`.
+    fn io_apic_mut(&mut self, gsi: u32) -> Option<&mut IoApic> {
+        self.io_apics
+            .iter_mut()
+            .filter_map(|io_apic| io_apic.as_mut())
+            .find(|io_apic| io_apic.handles(gsi))
+    }
+}
+
+struct LocalApic {
+    registers: MmioRegion,
+}
+
+impl LocalApic {
+    fn new(memory_controller: &mut MemoryController, address: usize) -> LocalApic {
+        use x86_64::registers::msr::{rdmsr, wrmsr};
+
+        unsafe {
+            let base = rdmsr(IA32_APIC_BASE);
+            wrmsr(IA32_APIC_BASE, base | APIC_BASE_ENABLE);
+        }
+
+        let mut registers = memory_controller
+            .map_mmio(address, LOCAL_APIC_SIZE, CacheMode::Uncached)
+            .expect("no room to map the local APIC");
+        // accept interrupts of every priority
+        registers.write::<u32>(LOCAL_APIC_TASK_PRIORITY, 0);
+        registers.write::<u32>(LOCAL_APIC_LVT_TIMER, LVT_MASKED);
+        registers.write::<u32>(LOCAL_APIC_LVT_ERROR, LVT_MASKED);
+        registers.write::<u32>(
+            LOCAL_APIC_SPURIOUS_VECTOR,
+            LOCAL_APIC_SOFTWARE_ENABLE | u32::from(SPURIOUS_VECTOR),
+        );
+
+        LocalApic {
+            registers: registers,
+        }
+    }
+
+    fn id(&self) -> u8 {
+        (self.registers.read::<u32>(LOCAL_APIC_ID) >> 24) as u8
+    }
+
+    fn end_of_interrupt(&mut self) {
+        self.registers.write::<u32>(LOCAL_APIC_END_OF_INTERRUPT, 0);
+    }
+}
+
+struct IoApic {
+    registers: MmioRegion,
+    gsi_base: u32,
+    entry_count: u32,
+}
+
+impl IoApic {
+    /// Maps the I/O APIC and masks all of its entries.
+    fn new(memory_controller: &mut MemoryController, info: &IoApicInfo) -> IoApic {
+        let registers = memory_controller
+            .map_mmio(info.address, IO_APIC_SIZE, CacheMode::Uncached)
+            .expect("no room to map the I/O APIC");
+        let mut io_apic = IoApic {
+            registers: registers,
+            gsi_base: info.gsi_base,
+            entry_count: 0,
+        };
+        io_apic.entry_count = ((io_apic.read(IO_APIC_VERSION) >> 16) & 0xff) + 1;
+
+        for gsi in info.gsi_base..info.gsi_base + io_apic.entry_count {
+            io_apic.set_redirection(gsi, REDIRECTION_MASKED);
+        }
+        io_apic
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi - self.gsi_base < self.entry_count
+    }
+
+    fn redirection(&mut self, gsi: u32) -> u64 {
+        let register = IO_APIC_REDIRECTION_TABLE + (gsi - self.gsi_base) * 2;
+        u64::from(self.read(register)) | u64::from(self.read(register + 1)) << 32
+    }
+
+    fn set_redirection(&mut self, gsi: u32, entry: u64) {
+        let register = IO_APIC_REDIRECTION_TABLE + (gsi - self.gsi_base) * 2;
+        // the low half holds the mask bit, so it is written last
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+
+    fn read(&mut self, register: u32) -> u32 {
+        self.registers.write::<u32>(IO_APIC_SELECT, register);
+        self.registers.read::<u32>(IO_APIC_WINDOW)
+    }
+
+    fn write(&mut self, register: u32, value: u32) {
+        self.registers.write::<u32>(IO_APIC_SELECT, register);
+        self.registers.write::<u32>(IO_APIC_WINDOW, value);
+    }
+}
@@ -1,10 +1,16 @@
 global start
+global stack_top
+global gdt64.higher_half_pointer
 extern long_mode_start                   ; this means that long_mode_start is coming from a seperate file
 
-section .text
+KERNEL_OFFSET equ 0xffffffff80000000     ; the kernel is linked here, see linker.ld
+
+; Everything outside .boot.text is linked into the higher half, so until
+; paging is enabled it has to be accessed at `address - KERNEL_OFFSET`.
+section .boot.text exec
 bits 32
 start:
-    mov esp, stack_top
+    mov esp, stack_top - KERNEL_OFFSET
 	mov edi, ebx
     
     call check_multiboot
@@ -14,7 +20,7 @@ start:
     call set_up_page_tables
     call enable_paging
 
-    lgdt [gdt64.pointer]
+    lgdt [gdt64.pointer - KERNEL_OFFSET]
 
     jmp gdt64.code:long_mode_start
 
@@ -85,27 +91,28 @@ check_long_mode:
     jmp error
 
 set_up_page_tables:
-	mov eax, p4_table
+	mov eax, p4_table - KERNEL_OFFSET
 	or eax, 0b11
-	mov [p4_table + 511 * 8], eax ; map P4 recursively
+	mov [p4_table - KERNEL_OFFSET + 510 * 8], eax ; map P4 recursively
 
-	mov eax, p3_table             ; map first P4 entry to P3 table via eax
+	mov eax, p3_table - KERNEL_OFFSET ; map first P4 entry to P3 table via eax
     or eax, 0b11                  ; set present + writable bits
-    mov [p4_table], eax           ; put P3 with present + writable set into P4
+    mov [p4_table - KERNEL_OFFSET], eax ; put P3 with present + writable set into P4
+    mov [p4_table - KERNEL_OFFSET + 511 * 8], eax ; the last P4 entry covers KERNEL_OFFSET
 
-    mov eax, p2_table             ; map first P3 entry to P2 table via eax
+    mov eax, p2_table - KERNEL_OFFSET ; map first P3 entry to P2 table via eax
     or eax, 0b11                  ; set present + writable bits
-    mov [p3_table], eax           ; same as P3 into P4 above
-                                  ; 
+    mov [p3_table - KERNEL_OFFSET], eax ; same as P3 into P4 above
+    mov [p3_table - KERNEL_OFFSET + 510 * 8], eax ; so the first GiB is also mapped at KERNEL_OFFSET
 
     ; map each P2 entry to a huge 2MiB page
     mov ecx, 0                    ; counter variable
 
 .map_p2_table:
-    mov eax, 0x20000000           ; 2MiB
+    mov eax, 0x200000             ; 2MiB
     mul ecx                       ; start address of ecx-th page - counter * 2MiB is the address of the start of this entry
     or eax, 0b10000011            ; present + writable + huge
-    mov [p2_table + ecx * 8], eax ; map ecx-th entry - i-th entry is start of P2 + (counter * 8 bytes) 8B is size of entry
+    mov [p2_table - KERNEL_OFFSET + ecx * 8], eax ; map ecx-th entry - i-th entry is start of P2 + (counter * 8 bytes) 8B is size of entry
 
     inc ecx                       ; increase counter
     cmp ecx, 512                   ; if counter == 512 then the whole P2 table is mapped
@@ -114,7 +121,7 @@ set_up_page_tables:
     ret
 
 enable_paging:
-    mov eax, p4_table
+    mov eax, p4_table - KERNEL_OFFSET
     mov cr3, eax                  ; load P4 to cr3 via eax
 
     mov eax, cr4                  ; put cr4 into eax
@@ -148,7 +155,10 @@ gdt64: ; this is a special pointer that is passed to the lgdt instrction
     dq (1<<43) | (1<<44 ) | (1<<47) | (1<<53) ; label that always points to the start of the code segment even if the GDT changes
 .pointer:
     dw $ - gdt64 - 1                          ; define a word as the current address minus the length of the GDT minus 1
-    dq gdt64                                  ; define a quad as the length of the GDT
+    dq gdt64 - KERNEL_OFFSET                  ; define a quad as the physical address of the GDT
+.higher_half_pointer:                         ; loaded again from the higher half, the physical address won't stay mapped
+    dw .pointer - gdt64 - 1
+    dq gdt64
 
 section .bss
 align 4096
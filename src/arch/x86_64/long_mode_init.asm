@@ -1,6 +1,10 @@
 global long_mode_start
+extern stack_top
+extern gdt64.higher_half_pointer
 
-section .text
+KERNEL_OFFSET equ 0xffffffff80000000     ; the kernel is linked here, see linker.ld
+
+section .boot.text exec
 bits 64
 long_mode_start:
     ; load 0 into all data segment registers
@@ -11,10 +15,24 @@ long_mode_start:
     mov fs, ax
     mov gs, ax
 
+    ; the rest of the kernel is linked into the higher half, which is too far
+    ; away for a relative jump
+    mov rax, higher_half_start
+    jmp rax
+
+section .text
+higher_half_start:
+    ; switch the stack and GDT over to their higher half addresses, the
+    ; identity mapping goes away once the kernel is remapped
+    mov rsp, stack_top
+    lgdt [gdt64.higher_half_pointer]
+
+	; terminate the frame pointer chain
+	xor rbp, rbp
 	extern rust_main
 	call rust_main
 
 	; print 'OKAY' to the screen
     mov rax, 0x2f592f412f4b2f4f ; this is 64bit - woo!
-    mov qword [0xb8000], rax
+    mov qword [KERNEL_OFFSET + 0xb8000], rax
     hlt
@@ -0,0 +1,320 @@
+use core::{fmt, mem};
+use x86_64::registers::control_regs;
+use x86_64::structures::idt::{ExceptionStackFrame, Idt};
+
+/// The general purpose registers when the exception happened, as saved by the
+/// stubs of `handler!`, followed by what the CPU pushed.
+#[derive(Debug)]
+#[repr(C)]
+pub struct ExceptionContext {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    /// Zero for exceptions that don't push an error code.
+    pub error_code: u64,
+    pub stack_frame: ExceptionStackFrame,
+}
+
+/// Creates an entry stub for an exception without an error code, which saves
+/// the registers and calls `$name` with the resulting `ExceptionContext`.
+macro_rules! handler {
+    ($name:ident) => {{
+        #[naked]
+        extern "C" fn stub() -> ! {
+            unsafe {
+                // stands in for the error code, so every context looks the same
+                asm!("push 0" :::: "intel", "volatile");
+                call_with_context!($name);
+            }
+        }
+        stub
+    }};
+}
+
+/// Like `handler!`, for exceptions that push an error code.
+macro_rules! handler_with_error_code {
+    ($name:ident) => {{
+        #[naked]
+        extern "C" fn stub() -> ! {
+            unsafe {
+                call_with_context!($name);
+            }
+        }
+        stub
+    }};
+}
+
+macro_rules! call_with_context {
+    ($name:ident) => {{
+        // the CPU aligns the stack to 16 bytes before pushing the frame, after
+        // the error code and 15 registers it needs another 8 bytes
+        asm!("push rax
+              push rbx
+              push rcx
+              push rdx
+              push rsi
+              push rdi
+              push rbp
+              push r8
+              push r9
+              push r10
+              push r11
+              push r12
+              push r13
+              push r14
+              push r15
+              mov rdi, rsp
+              sub rsp, 8
+              call $0
+              add rsp, 8
+              pop r15
+              pop r14
+              pop r13
+              pop r12
+              pop r11
+              pop r10
+              pop r9
+              pop r8
+              pop rbp
+              pop rdi
+              pop rsi
+              pop rdx
+              pop rcx
+              pop rbx
+              pop rax
+              add rsp, 8
+              iretq"
+              :: "i"($name as extern "C" fn(&mut $crate::interrupts::exceptions::ExceptionContext))
+              :: "intel", "volatile");
+        ::core::intrinsics::unreachable();
+    }};
+}
+
+/// Turns a stub of `handler!` into whatever handler type `F` the `Idt` entry
+/// wants. Only the address of the stub ends up in the entry.
+pub unsafe fn as_handler<F>(stub: extern "C" fn() -> !) -> F {
+    mem::transmute_copy(&stub)
+}
+
+/// Sets the handlers of every exception that isn't handled in `mod.rs`.
+pub fn set_handlers(idt: &mut Idt) {
+    unsafe {
+        idt.divide_by_zero
+            .set_handler_fn(as_handler(handler!(divide_by_zero_handler)));
+        idt.overflow
+            .set_handler_fn(as_handler(handler!(overflow_handler)));
+        idt.bound_range_exceeded
+            .set_handler_fn(as_handler(handler!(bound_range_exceeded_handler)));
+        idt.invalid_opcode
+            .set_handler_fn(as_handler(handler!(invalid_opcode_handler)));
+        idt.device_not_available
+            .set_handler_fn(as_handler(handler!(device_not_available_handler)));
+        idt.invalid_tss
+            .set_handler_fn(as_handler(handler_with_error_code!(invalid_tss_handler)));
+        idt.segment_not_present
+            .set_handler_fn(as_handler(handler_with_error_code!(
+                segment_not_present_handler
+            )));
+        idt.stack_segment_fault
+            .set_handler_fn(as_handler(handler_with_error_code!(
+                stack_segment_fault_handler
+            )));
+        idt.general_protection_fault
+            .set_handler_fn(as_handler(handler_with_error_code!(
+                general_protection_fault_handler
+            )));
+        idt.x87_floating_point
+            .set_handler_fn(as_handler(handler!(x87_floating_point_handler)));
+        idt.alignment_check
+            .set_handler_fn(as_handler(handler_with_error_code!(
+                alignment_check_handler
+            )));
+        idt.machine_check
+            .set_handler_fn(as_handler(handler!(machine_check_handler)));
+        idt.simd_floating_point
+            .set_handler_fn(as_handler(handler!(simd_floating_point_handler)));
+        idt.virtualization
+            .set_handler_fn(as_handler(handler!(virtualization_handler)));
+        idt.security_exception
+            .set_handler_fn(as_handler(handler_with_error_code!(
+                security_exception_handler
+            )));
+    }
+    // these print the frame and carry on, the registers are of no interest
+    idt.debug.set_handler_fn(debug_handler);
+    idt.non_maskable_interrupt
+        .set_handler_fn(non_maskable_interrupt_handler);
+}
+
+/// Prints the state of the CPU when the exception happened and halts. The
+/// handler prints the name of the exception and anything it knows about the
+/// cause first.
+pub fn halt_with_report(context: &ExceptionContext) -> ! {
+    let stack_frame = &context.stack_frame;
+    let code_segment = stack_frame.code_segment;
+    println!(
+        "rip: {:#018x}  cs: {:#x} (ring {})  rflags: {:#x}",
+        stack_frame.instruction_pointer.0,
+        code_segment,
+        code_segment & 0b11,
+        stack_frame.cpu_flags
+    );
+    println!(
+        "rsp: {:#018x}  ss: {:#x}",
+        stack_frame.stack_pointer.0, stack_frame.stack_segment
+    );
+    println!(
+        "rax: {:#018x}  rbx: {:#018x}  rcx: {:#018x}",
+        context.rax, context.rbx, context.rcx
+    );
+    println!(
+        "rdx: {:#018x}  rsi: {:#018x}  rdi: {:#018x}",
+        context.rdx, context.rsi, context.rdi
+    );
+    println!(
+        "rbp: {:#018x}  r8:  {:#018x}  r9:  {:#018x}",
+        context.rbp, context.r8, context.r9
+    );
+    println!(
+        "r10: {:#018x}  r11: {:#018x}  r12: {:#018x}",
+        context.r10, context.r11, context.r12
+    );
+    println!(
+        "r13: {:#018x}  r14: {:#018x}  r15: {:#018x}",
+        context.r13, context.r14, context.r15
+    );
+    println!(
+        "cr0: {:#x}  cr2: {:#x}  cr3: {:#x}  cr4: {:#x}",
+        control_regs::cr0().bits(),
+        control_regs::cr2().0,
+        control_regs::cr3().0,
+        control_regs::cr4().bits()
+    );
+    loop {}
+}
+
+fn crash(name: &str, context: &ExceptionContext) -> ! {
+    println!("EXCEPTION: {}", name);
+    halt_with_report(context)
+}
+
+fn crash_with_selector(name: &str, context: &ExceptionContext) -> ! {
+    println!("EXCEPTION: {}", name);
+    println!("caused by {}", SelectorErrorCode(context.error_code));
+    halt_with_report(context)
+}
+
+/// The error code pushed by exceptions that concern a segment selector or an
+/// IDT gate.
+struct SelectorErrorCode(u64);
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code = self.0;
+        if code == 0 {
+            return write!(f, "no particular segment");
+        }
+
+        let index = (code >> 3) & 0x1fff;
+        if code & (1 << 1) != 0 {
+            write!(f, "IDT vector {}", index)?;
+        } else if code & (1 << 2) != 0 {
+            write!(f, "LDT selector {:#x}", index << 3)?;
+        } else {
+            write!(f, "GDT selector {:#x}", index << 3)?;
+        }
+        if code & (1 << 0) != 0 {
+            write!(f, " during delivery of an external event")?;
+        }
+        Ok(())
+    }
+}
+
+extern "C" fn divide_by_zero_handler(context: &mut ExceptionContext) {
+    crash("DIVIDE ERROR", context);
+}
+
+extern "x86-interrupt" fn debug_handler(stack_frame: &mut ExceptionStackFrame) {
+    println!("EXCEPTION: DEBUG\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn non_maskable_interrupt_handler(stack_frame: &mut ExceptionStackFrame) {
+    // usually a hardware failure, which isn't necessarily fatal
+    println!("EXCEPTION: NON-MASKABLE INTERRUPT\n{:#?}", stack_frame);
+}
+
+extern "C" fn overflow_handler(context: &mut ExceptionContext) {
+    crash("OVERFLOW", context);
+}
+
+extern "C" fn bound_range_exceeded_handler(context: &mut ExceptionContext) {
+    crash("BOUND RANGE EXCEEDED", context);
+}
+
+extern "C" fn invalid_opcode_handler(context: &mut ExceptionContext) {
+    crash("INVALID OPCODE", context);
+}
+
+extern "C" fn device_not_available_handler(context: &mut ExceptionContext) {
+    crash("DEVICE NOT AVAILABLE", context);
+}
+
+extern "C" fn invalid_tss_handler(context: &mut ExceptionContext) {
+    crash_with_selector("INVALID TSS", context);
+}
+
+extern "C" fn segment_not_present_handler(context: &mut ExceptionContext) {
+    crash_with_selector("SEGMENT NOT PRESENT", context);
+}
+
+extern "C" fn stack_segment_fault_handler(context: &mut ExceptionContext) {
+    crash_with_selector("STACK-SEGMENT FAULT", context);
+}
+
+extern "C" fn general_protection_fault_handler(context: &mut ExceptionContext) {
+    crash_with_selector("GENERAL PROTECTION FAULT", context);
+}
+
+extern "C" fn x87_floating_point_handler(context: &mut ExceptionContext) {
+    crash("X87 FLOATING POINT", context);
+}
+
+extern "C" fn alignment_check_handler(context: &mut ExceptionContext) {
+    println!("EXCEPTION: ALIGNMENT CHECK");
+    // alignment is only checked in ring 3, and the error code is always zero
+    println!(
+        "unaligned access from user mode, error code {:#x}",
+        context.error_code
+    );
+    halt_with_report(context)
+}
+
+extern "C" fn machine_check_handler(context: &mut ExceptionContext) {
+    crash("MACHINE CHECK", context);
+}
+
+extern "C" fn simd_floating_point_handler(context: &mut ExceptionContext) {
+    crash("SIMD FLOATING POINT", context);
+}
+
+extern "C" fn virtualization_handler(context: &mut ExceptionContext) {
+    crash("VIRTUALIZATION", context);
+}
+
+extern "C" fn security_exception_handler(context: &mut ExceptionContext) {
+    println!("EXCEPTION: SECURITY EXCEPTION");
+    println!("error code: {:#x}", context.error_code);
+    halt_with_report(context)
+}
@@ -0,0 +1,225 @@
+use spin::Mutex;
+use x86_64::structures::idt::{ExceptionStackFrame, HandlerFunc};
+
+// The ISA IRQs, which are the lines of the two chained PICs.
+pub const IRQ_COUNT: usize = 16;
+// How many devices can share one line.
+const MAX_HANDLERS_PER_IRQ: usize = 4;
+
+/// Called with interrupts disabled whenever the IRQ it is registered for
+/// fires. A shared line calls every handler registered on it, so each has to
+/// check whether its device actually raised the interrupt.
+pub type IrqHandler = fn();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    InvalidIrq(u8),
+    /// Every handler slot of the line is taken.
+    LineFull(u8),
+    NotRegistered(u8),
+}
+
+static HANDLERS: Mutex<[[Option<IrqHandler>; MAX_HANDLERS_PER_IRQ]; IRQ_COUNT]> =
+    Mutex::new([[None; MAX_HANDLERS_PER_IRQ]; IRQ_COUNT]);
+// How often each IRQ fired, not counting spurious interrupts.
+static COUNTS: Mutex<[usize; IRQ_COUNT]> = Mutex::new([0; IRQ_COUNT]);
+
+/// Calls `handler` whenever `irq` fires, after any handlers already
+/// registered for it. Unmasks `irq` if it was masked.
+pub fn register_irq(irq: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    if irq as usize >= IRQ_COUNT {
+        return Err(IrqError::InvalidIrq(irq));
+    }
+    without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        match handlers[irq as usize].iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(handler);
+                set_masked(irq, false);
+                Ok(())
+            }
+            None => Err(IrqError::LineFull(irq)),
+        }
+    })
+}
+
+/// Stops calling `handler` for `irq`. Masks `irq` once it has no handlers
+/// left.
+pub fn unregister_irq(irq: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    if irq as usize >= IRQ_COUNT {
+        return Err(IrqError::InvalidIrq(irq));
+    }
+    without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        let handlers = &mut handlers[irq as usize];
+        match handlers.iter().position(|&slot| slot == Some(handler)) {
+            Some(index) => {
+                // keep the remaining handlers in registration order
+                for i in index..MAX_HANDLERS_PER_IRQ - 1 {
+                    handlers[i] = handlers[i + 1];
+                }
+                handlers[MAX_HANDLERS_PER_IRQ - 1] = None;
+                if handlers[0].is_none() {
+                    set_masked(irq, true);
+                }
+                Ok(())
+            }
+            None => Err(IrqError::NotRegistered(irq)),
+        }
+    })
+}
+
+/// Stops `irq` from firing until `unmask_irq`, e.g. while its device is being
+/// set up. Registering a handler unmasks it as well.
+pub fn mask_irq(irq: u8) -> Result<(), IrqError> {
+    if irq as usize >= IRQ_COUNT {
+        return Err(IrqError::InvalidIrq(irq));
+    }
+    without_interrupts(|| set_masked(irq, true));
+    Ok(())
+}
+
+/// Lets `irq` fire again.
+pub fn unmask_irq(irq: u8) -> Result<(), IrqError> {
+    if irq as usize >= IRQ_COUNT {
+        return Err(IrqError::InvalidIrq(irq));
+    }
+    without_interrupts(|| set_masked(irq, false));
+    Ok(())
+}
+
+/// The IRQs waiting at the PICs to be handled, IRQ 0 in the lowest bit.
+pub fn pic_irr() -> u16 {
+    without_interrupts(|| unsafe { super::PICS.lock().interrupt_requests() })
+}
+
+/// The IRQs the PICs are waiting for an end of interrupt for, IRQ 0 in the
+/// lowest bit.
+pub fn pic_isr() -> u16 {
+    without_interrupts(|| unsafe { super::PICS.lock().interrupts_in_service() })
+}
+
+/// The masks of all IRQs, IRQ 0 in the lowest bit, at whichever interrupt
+/// controller delivers them.
+pub fn masks() -> u16 {
+    without_interrupts(|| match *super::APIC.lock() {
+        Some(ref mut apic) => apic.masks(),
+        None => unsafe { super::PICS.lock().masks() },
+    })
+}
+
+fn set_masked(irq: u8, masked: bool) {
+    match *super::APIC.lock() {
+        Some(ref mut apic) => if masked {
+            apic.mask(irq)
+        } else {
+            apic.unmask(irq)
+        },
+        None => unsafe {
+            let mut pics = super::PICS.lock();
+            if masked {
+                pics.mask(irq)
+            } else {
+                pics.unmask(irq)
+            }
+        },
+    }
+}
+
+/// Acknowledges `irq` at whichever interrupt controller delivered it.
+fn end_of_interrupt(irq: u8) {
+    match *super::APIC.lock() {
+        Some(ref mut apic) => apic.end_of_interrupt(),
+        None => unsafe {
+            super::PICS
+                .lock()
+                .notify_end_of_interrupt(super::PIC_1_OFFSET + irq)
+        },
+    }
+}
+
+/// Runs `f` with interrupts disabled, so an IRQ can't try to take a lock that
+/// `f` holds.
+pub fn without_interrupts<F, T>(f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    // the interrupt flag of RFLAGS
+    const INTERRUPT_FLAG: u64 = 1 << 9;
+
+    let flags: u64;
+    unsafe {
+        asm!("pushfq; pop $0" : "=r"(flags) ::: "volatile");
+        asm!("cli" :::: "volatile");
+    }
+    let result = f();
+    if flags & INTERRUPT_FLAG != 0 {
+        unsafe { asm!("sti" :::: "volatile") };
+    }
+    result
+}
+
+/// The number of handlers registered for each IRQ.
+pub fn handler_counts() -> [usize; IRQ_COUNT] {
+    let handlers = without_interrupts(|| *HANDLERS.lock());
+    let mut counts = [0; IRQ_COUNT];
+    for (count, handlers) in counts.iter_mut().zip(handlers.iter()) {
+        *count = handlers.iter().filter(|handler| handler.is_some()).count();
+    }
+    counts
+}
+
+/// How often each IRQ fired.
+pub fn irq_counts() -> [usize; IRQ_COUNT] {
+    without_interrupts(|| *COUNTS.lock())
+}
+
+fn dispatch(irq: u8) {
+    // with the APIC, spurious interrupts arrive at vectors of their own, see
+    // `enable_apic`
+    let delivered_by_pics = super::APIC.lock().is_none();
+    if delivered_by_pics && unsafe { super::PICS.lock().is_spurious(super::PIC_1_OFFSET + irq) } {
+        return;
+    }
+    COUNTS.lock()[irq as usize] += 1;
+
+    // copied out, so handlers can register and unregister handlers themselves
+    let handlers = HANDLERS.lock()[irq as usize];
+    for handler in handlers.iter().filter_map(|&handler| handler) {
+        handler();
+    }
+    end_of_interrupt(irq);
+}
+
+macro_rules! irq_stubs {
+    ($($stub:ident = $irq:expr),*) => {
+        $(
+            extern "x86-interrupt" fn $stub(_stack_frame: &mut ExceptionStackFrame) {
+                dispatch($irq);
+            }
+        )*
+
+        /// The IDT entry of every IRQ, which calls the handlers registered for
+        /// it.
+        pub static STUBS: [HandlerFunc; IRQ_COUNT] = [$($stub),*];
+    };
+}
+
+irq_stubs!(
+    irq0 = 0,
+    irq1 = 1,
+    irq2 = 2,
+    irq3 = 3,
+    irq4 = 4,
+    irq5 = 5,
+    irq6 = 6,
+    irq7 = 7,
+    irq8 = 8,
+    irq9 = 9,
+    irq10 = 10,
+    irq11 = 11,
+    irq12 = 12,
+    irq13 = 13,
+    irq14 = 14,
+    irq15 = 15
+);
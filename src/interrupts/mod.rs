@@ -1,16 +1,39 @@
-use memory::MemoryController;
+use acpi;
+use apic::{self, Apic};
+use memory::{self, MemoryController};
 use pic::ChainedPics;
 use spin::{Mutex, Once};
 
+use self::exceptions::{as_handler, ExceptionContext};
 use x86_64::instructions::port::inb;
-use x86_64::structures::idt::{ExceptionStackFrame, Idt};
+use x86_64::structures::idt::{ExceptionStackFrame, Idt, PageFaultErrorCode};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtualAddress;
 
+pub use self::irq::{
+    irq_counts, mask_irq, pic_irr, pic_isr, register_irq, unmask_irq, unregister_irq, IrqError,
+    IrqHandler,
+};
+
+#[macro_use]
+mod exceptions;
 mod gdt;
+mod irq;
 
-static PICS: Mutex<ChainedPics> = Mutex::new(unsafe { ChainedPics::new(0x20, 0x28) });
+// The vectors the IRQs of the two PICs are remapped to.
+const PIC_1_OFFSET: u8 = 0x20;
+const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+static PICS: Mutex<ChainedPics> =
+    Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+// Where the PICs are moved once the I/O APICs deliver IRQs on their vectors.
+// Only their spurious interrupts still arrive, at `dummy_handler`.
+const DISABLED_PIC_OFFSET: u8 = 0xe0;
+// Set once IRQs are delivered by the APIC instead of the PICs.
+static APIC: Mutex<Option<Apic>> = Mutex::new(None);
 const DOUBLE_FAULT_IST_INDEX: usize = 0;
+// Page faults get a stack of their own, so overflowing a kernel stack into
+// its guard page can still be reported instead of double faulting.
+const PAGE_FAULT_IST_INDEX: usize = 1;
 static TSS: Once<TaskStateSegment> = Once::new();
 static GDT: Once<gdt::Gdt> = Once::new();
 
@@ -20,18 +43,21 @@ lazy_static! {
 
         unsafe {
             idt.double_fault
-                .set_handler_fn(double_fault_handler)
+                .set_handler_fn(as_handler(handler_with_error_code!(double_fault_handler)))
                 .set_stack_index(DOUBLE_FAULT_IST_INDEX as u16);
+            idt.page_fault
+                .set_handler_fn(as_handler(handler_with_error_code!(page_fault_handler)))
+                .set_stack_index(PAGE_FAULT_IST_INDEX as u16);
         }
 
         idt.breakpoint.set_handler_fn(breakpoint_handler);
+        exceptions::set_handlers(&mut idt);
 
-        for i in 0..224 {
-            if i == 1 {
-                idt.interrupts[i].set_handler_fn(keyboard_handler);
-            } else {
-                idt.interrupts[i].set_handler_fn(dummy_handler);
-            }
+        for (irq, &stub) in irq::STUBS.iter().enumerate() {
+            idt.interrupts[irq].set_handler_fn(stub);
+        }
+        for i in irq::IRQ_COUNT..224 {
+            idt.interrupts[i].set_handler_fn(dummy_handler);
         }
 
         idt
@@ -46,11 +72,15 @@ pub unsafe fn init(memory_controller: &mut MemoryController) {
     let double_fault_stack = memory_controller
         .alloc_stack(1)
         .expect("cold not allocate double fault stack");
+    let page_fault_stack = memory_controller
+        .alloc_stack(2)
+        .expect("could not allocate page fault stack");
 
     let tss = TSS.call_once(|| {
         let mut tss = TaskStateSegment::new();
         tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX] =
             VirtualAddress(double_fault_stack.top());
+        tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX] = VirtualAddress(page_fault_stack.top());
         tss
     });
 
@@ -70,21 +100,159 @@ pub unsafe fn init(memory_controller: &mut MemoryController) {
     load_tss(tss_selector);
 
     IDT.load();
+
+    register_irq(1, keyboard_handler).expect("could not register the keyboard handler");
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApicError {
+    NotSupported,
+    NoMadt,
+    NoIoApic,
+}
+
+/// Delivers IRQs through the local APIC and the I/O APICs instead of the 8259
+/// PICs, which are masked completely and moved to vectors of their own. IRQs
+/// keep their vectors, handlers and masks.
+pub fn enable_apic(memory_controller: &mut MemoryController) -> Result<(), ApicError> {
+    if !apic::is_supported() {
+        return Err(ApicError::NotSupported);
+    }
+    let madt = acpi::find_madt(memory_controller).ok_or(ApicError::NoMadt)?;
+    if madt.io_apics.iter().all(|io_apic| io_apic.is_none()) {
+        return Err(ApicError::NoIoApic);
+    }
+
+    let mut apic = Apic::new(memory_controller, &madt, PIC_1_OFFSET);
+    irq::without_interrupts(|| {
+        for (irq, &count) in irq::handler_counts().iter().enumerate() {
+            if count > 0 {
+                apic.unmask(irq as u8);
+            }
+        }
+        if madt.has_8259 {
+            unsafe { PICS.lock().remap(DISABLED_PIC_OFFSET, DISABLED_PIC_OFFSET + 8) };
+        }
+        *APIC.lock() = Some(apic);
+    });
+    Ok(())
+}
+
+/// How many spurious interrupts the master and the slave PIC raised.
+pub fn spurious_interrupts() -> [usize; 2] {
+    irq::without_interrupts(|| PICS.lock().spurious_counts())
+}
+
+/// Prints how often each IRQ fired, in the style of `/proc/interrupts`.
+pub fn print_interrupts() {
+    let counts = irq_counts();
+    let handler_counts = irq::handler_counts();
+    let masks = irq::masks();
+
+    println!("IRQ      COUNT  STATE    HANDLERS");
+    for irq in 0..irq::IRQ_COUNT {
+        let state = if masks & (1 << irq) != 0 {
+            "masked"
+        } else {
+            "enabled"
+        };
+        println!(
+            "{:>3} {:>10}  {:<8} {}",
+            irq, counts[irq], state, handler_counts[irq]
+        );
+    }
+    let spurious = spurious_interrupts();
+    println!(
+        "SPU {:>10}  master {}, slave {}",
+        spurious[0] + spurious[1],
+        spurious[0],
+        spurious[1]
+    );
+    println!("PIC IRR {:#06x}  ISR {:#06x}", pic_irr(), pic_isr());
 }
 
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut ExceptionStackFrame) {
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: &mut ExceptionStackFrame,
-    _error_code: u64,
-) {
-    println!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
-    loop {}
+extern "C" fn double_fault_handler(context: &mut ExceptionContext) {
+    println!("EXCEPTION: DOUBLE FAULT");
+    exceptions::halt_with_report(context)
+}
+
+extern "C" fn page_fault_handler(context: &mut ExceptionContext) {
+    use x86_64::registers::control_regs;
+
+    let address = control_regs::cr2().0;
+    let error_code = PageFaultErrorCode::from_bits_truncate(context.error_code);
+
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+        && error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+        && memory::resolve_cow_fault(address)
+    {
+        // the page was shared copy-on-write, returning retries the write
+        return;
+    }
+    if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+        && memory::map_heap_page(address)
+    {
+        // the heap is backed lazily, returning retries the access
+        return;
+    }
+
+    let access = if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        "instruction fetch"
+    } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+        "write"
+    } else {
+        "read"
+    };
+    let mode = if error_code.contains(PageFaultErrorCode::USER_MODE) {
+        "user"
+    } else {
+        "kernel"
+    };
+    let cause = if error_code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+        "reserved bit set in a page table entry"
+    } else if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        "protection violation"
+    } else {
+        "page not present"
+    };
+
+    println!("EXCEPTION: PAGE FAULT at {:#x}", address);
+    println!("{} from {} mode, {}", access, mode, cause);
+
+    let (vma, physical_address) = memory::describe_address(address);
+    match vma {
+        Some(vma) => println!("in {}", vma),
+        None => println!("outside of any memory area"),
+    }
+    match physical_address {
+        Some(physical_address) => println!("page is mapped to {:#x}", physical_address),
+        None => println!("page is not mapped"),
+    }
+    if let Some(stack) = memory::overflowed_stack(address) {
+        println!(
+            "stack overflow in stack {} (bounds {:#x}..{:#x})",
+            stack.id, stack.bottom, stack.top
+        );
+    } else if let Some(vma) = vma {
+        if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) && !vma.permissions.writable {
+            println!("write to a read-only area");
+        } else if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+            && !vma.permissions.executable
+        {
+            println!("instruction fetch from a non-executable area");
+        } else if error_code.contains(PageFaultErrorCode::USER_MODE) && !vma.permissions.user {
+            println!("user mode access to a kernel area");
+        }
+    }
+
+    exceptions::halt_with_report(context)
 }
 
-extern "x86-interrupt" fn keyboard_handler(stack_frame: &mut ExceptionStackFrame) {
+fn keyboard_handler() {
     use drivers::keyboard::read_scancode_from_keyboard;
 
     if let Some(input) = read_scancode_from_keyboard() {
@@ -95,13 +263,9 @@ extern "x86-interrupt" fn keyboard_handler(stack_frame: &mut ExceptionStackFrame
             print!("{}", input);
         }
     }
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(0x21 as u8);
-    }
 }
 
 extern "x86-interrupt" fn dummy_handler(stack_frame: &mut ExceptionStackFrame) {
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(0x20 as u8);
-    }
+    // no IRQ is routed to these vectors, and the spurious interrupts of the
+    // local APIC and the disabled PICs must not be acknowledged
 }
@@ -5,9 +5,15 @@
 #![feature(alloc)]
 #![feature(abi_x86_interrupt)]
 #![feature(asm)]
+#![feature(naked_functions)]
+#![feature(core_intrinsics)]
 #![feature(alloc_error_handler)]
 #![no_std]
 
+// The unit tests run on the host, where std provides the runtime.
+#[cfg(test)]
+extern crate std;
+
 extern crate hole_list_allocator as allocator;
 
 #[macro_use]
@@ -29,14 +35,22 @@ extern crate cpuio;
 
 #[macro_use]
 mod vga_buffer;
+mod acpi;
+mod apic;
 mod drivers;
 mod interrupts;
 mod memory;
 mod pic;
 
-pub const HEAP_START: usize = 0o_000_001_000_000_0000; // heap starts at the second P3 entry
-pub const HEAP_SIZE: usize = 100 * 1024; // 100KiB
+// The lower half of the address space is left to user programs.
+pub const KERNEL_OFFSET: usize = 0xffffffff_80000000; // the kernel is linked here, see linker.ld
+pub const PHYSICAL_MEMORY_OFFSET: usize = 0xffff8000_00000000; // all RAM, mapped at P4 entry 256
+pub const HEAP_START: usize = 0o_177777_775_000_000_000_0000; // heap starts at P4 entry 509
+pub const HEAP_INITIAL_SIZE: usize = 100 * 1024; // 100KiB
+pub const HEAP_MAX_SIZE: usize = 16 * 1024 * 1024; // 16MiB, the heap never grows past this
+pub const MMIO_START: usize = 0o_177777_774_000_000_000_0000; // device memory, from P4 entry 508
 
+#[cfg(not(test))]
 #[global_allocator]
 static GLOBAL_ALLOC: allocator::Allocator = allocator::Allocator;
 
@@ -46,17 +60,28 @@ pub extern "C" fn rust_main(multiboot_information_address: usize) {
 
     vga_buffer::clear_screen();
 
-    let boot_info = unsafe { multiboot2::load(multiboot_information_address) };
+    // the boot page tables map the first GiB of physical memory, which
+    // includes the multiboot information, at KERNEL_OFFSET
+    let boot_info = unsafe { multiboot2::load(KERNEL_OFFSET + multiboot_information_address) };
     enable_nxe_bit();
     enable_write_protect_bit();
 
-    // remap the kernel, set up the guard page and map the heap pages
+    // remap the kernel and set up the guard page
     let mut memory_controller = memory::init(&boot_info);
 
     unsafe {
         interrupts::init(&mut memory_controller);
     }
 
+    // heap pages are mapped by the page fault handler, so the heap can't be
+    // used until the interrupts are set up
+    memory::init_heap();
+
+    match interrupts::enable_apic(&mut memory_controller) {
+        Ok(()) => println!("IRQs are delivered by the APIC"),
+        Err(error) => println!("IRQs are delivered by the 8259 PICs: {:?}", error),
+    }
+
     unsafe {
         asm!("sti");
     }
@@ -81,11 +106,14 @@ fn enable_write_protect_bit() {
     unsafe { cr0_write(cr0() | Cr0::WRITE_PROTECT) };
 }
 
+#[cfg(not(test))]
 #[lang = "eh_personality"]
 extern "C" fn eh_personality() {}
 
+#[cfg(not(test))]
 use core::panic::PanicInfo;
 
+#[cfg(not(test))]
 #[panic_implementation]
 #[no_mangle]
 pub fn panic(panic_info: &PanicInfo) -> ! {
@@ -104,7 +132,14 @@ pub fn panic(panic_info: &PanicInfo) -> ! {
     }
 }
 
+#[cfg(not(test))]
 #[alloc_error_handler]
-fn alloc_error(_: core::alloc::Layout) -> ! {
+fn alloc_error(layout: core::alloc::Layout) -> ! {
+    println!(
+        "\n\nOUT OF MEMORY: {} bytes aligned to {} requested",
+        layout.size(),
+        layout.align()
+    );
+    memory::print_heap_stats();
     panic!("Alloc error!");
 }
@@ -0,0 +1,221 @@
+use core::mem::size_of;
+use core::{cmp, slice};
+use memory::{Frame, FrameAllocator, PAGE_SIZE};
+use multiboot2::MemoryAreaIter;
+
+// The bitmap can describe up to 4GiB of physical memory, anything above that is ignored.
+const MAX_FRAMES: usize = 4 * 1024 * 1024 * 1024 / PAGE_SIZE;
+const BITS_PER_WORD: usize = 64;
+const FULL_WORD: u64 = !0;
+
+// One bit per frame, set if the frame is in use. This lives in the .bss so it
+// is mapped along with the rest of the kernel and needs no allocation itself.
+static mut FRAME_BITMAP: [u64; MAX_FRAMES / BITS_PER_WORD] = [0; MAX_FRAMES / BITS_PER_WORD];
+
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    // the number of references to each used frame beyond the first, see
+    // `share_frame`. Only allocated once a frame is first shared
+    shares: Option<&'static mut [u32]>,
+    frame_count: usize,
+    free_count: usize,
+    next_word: usize,
+}
+
+impl FrameAllocator for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        let word_count = self.bitmap.len();
+        for i in 0..word_count {
+            let word_index = (self.next_word + i) % word_count;
+            let word = self.bitmap[word_index];
+            if word != FULL_WORD {
+                // the spare bits past `frame_count` are always set, so this frame exists
+                let number = word_index * BITS_PER_WORD + (!word).trailing_zeros() as usize;
+                self.mark_used(number);
+                self.next_word = word_index;
+                return Some(Frame { number: number });
+            }
+        }
+        None
+    }
+
+    /// Drops a reference to `frame`, which is only freed once the last one is
+    /// gone.
+    fn deallocate_frame(&mut self, frame: Frame) {
+        assert!(
+            self.is_used(frame.number),
+            "frame {:#x} freed twice",
+            frame.start_address()
+        );
+        if let Some(ref mut shares) = self.shares {
+            if shares[frame.number] > 0 {
+                shares[frame.number] -= 1;
+                return;
+            }
+        }
+        self.mark_free(frame.number);
+        self.next_word = cmp::min(self.next_word, frame.number / BITS_PER_WORD);
+    }
+}
+
+impl BitmapFrameAllocator {
+    pub fn new(memory_areas: MemoryAreaIter) -> BitmapFrameAllocator {
+        let frame_count = memory_areas
+            .clone()
+            .map(|area| ((area.start_address() + area.size()) as usize) / PAGE_SIZE)
+            .max()
+            .unwrap_or(0);
+        let frame_count = cmp::min(frame_count, MAX_FRAMES);
+        let word_count = (frame_count + BITS_PER_WORD - 1) / BITS_PER_WORD;
+
+        let mut allocator = BitmapFrameAllocator {
+            bitmap: unsafe { &mut FRAME_BITMAP[..word_count] },
+            shares: None,
+            frame_count: frame_count,
+            free_count: 0,
+            next_word: 0,
+        };
+
+        // everything starts out used, then the available areas are freed
+        for word in allocator.bitmap.iter_mut() {
+            *word = FULL_WORD;
+        }
+        for area in memory_areas {
+            // only whole frames inside the area can be used
+            let first = (area.start_address() as usize + PAGE_SIZE - 1) / PAGE_SIZE;
+            let last = cmp::min(
+                ((area.start_address() + area.size()) as usize) / PAGE_SIZE,
+                frame_count,
+            );
+            for number in first..last {
+                if allocator.is_used(number) {
+                    allocator.mark_free(number);
+                }
+            }
+        }
+
+        // never hand out frame 0, so a zeroed entry can't look like a valid frame
+        if !allocator.is_used(0) {
+            allocator.mark_used(0);
+        }
+
+        allocator
+    }
+
+    /// Marks every frame overlapping `start..end` as used so it is never allocated.
+    pub fn reserve_range(&mut self, start: usize, end: usize) {
+        if end <= start {
+            return;
+        }
+        let start_frame = Frame::containing_address(start);
+        let end_frame = Frame::containing_address(end - 1);
+        for frame in Frame::range_inclusive(start_frame, end_frame) {
+            if frame.number < self.frame_count && !self.is_used(frame.number) {
+                self.mark_used(frame.number);
+            }
+        }
+    }
+
+    /// Allocates `count` physically contiguous frames, the first of which is
+    /// aligned to `align` frames.
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<Frame> {
+        assert!(count > 0, "cannot allocate zero frames");
+        assert!(align.is_power_of_two(), "alignment must be a power of two");
+
+        let mut start = 0;
+        while start + count <= self.frame_count {
+            match (start..start + count).rev().find(|&number| self.is_used(number)) {
+                Some(used) => start = (used + align) & !(align - 1),
+                None => {
+                    for number in start..start + count {
+                        self.mark_used(number);
+                    }
+                    return Some(Frame { number: start });
+                }
+            }
+        }
+        None
+    }
+
+    /// Frees `count` contiguous frames starting at `first`.
+    pub fn deallocate_contiguous(&mut self, first: Frame, count: usize) {
+        let last = Frame {
+            number: first.number + count - 1,
+        };
+        for frame in Frame::range_inclusive(first, last) {
+            self.deallocate_frame(frame);
+        }
+    }
+
+    /// Adds a reference to the allocated `frame`, e.g. because it is mapped
+    /// copy-on-write into another address space. Every reference is dropped
+    /// with `deallocate_frame`.
+    pub fn share_frame(&mut self, frame: &Frame) {
+        assert!(
+            frame.number < self.frame_count && self.is_used(frame.number),
+            "frame {:#x} is not allocated",
+            frame.start_address()
+        );
+        if self.shares.is_none() {
+            let shares = self.allocate_shares();
+            self.shares = Some(shares);
+        }
+        let shares = &mut self.shares.as_mut().unwrap()[frame.number];
+        *shares = shares.checked_add(1).expect("frame shared too often");
+    }
+
+    /// How many times `frame` has to be deallocated before it is free again.
+    pub fn reference_count(&self, frame: &Frame) -> usize {
+        if frame.number < self.frame_count && self.is_used(frame.number) {
+            self.shares.as_ref().map_or(0, |shares| shares[frame.number]) as usize + 1
+        } else {
+            0
+        }
+    }
+
+    pub fn free_frame_count(&self) -> usize {
+        self.free_count
+    }
+
+    pub fn used_frame_count(&self) -> usize {
+        self.frame_count - self.free_count
+    }
+
+    pub fn total_frame_count(&self) -> usize {
+        self.frame_count
+    }
+
+    /// Takes the share counts from the frames this allocator hands out. They
+    /// are reached through the direct map, so this only works once that is
+    /// set up.
+    fn allocate_shares(&mut self) -> &'static mut [u32] {
+        use PHYSICAL_MEMORY_OFFSET;
+
+        let size = self.frame_count * size_of::<u32>();
+        let first = self
+            .allocate_contiguous((size + PAGE_SIZE - 1) / PAGE_SIZE, 1)
+            .expect("no memory for the frame share counts");
+        unsafe {
+            let address = PHYSICAL_MEMORY_OFFSET + first.start_address();
+            let shares = slice::from_raw_parts_mut(address as *mut u32, self.frame_count);
+            for share in shares.iter_mut() {
+                *share = 0;
+            }
+            shares
+        }
+    }
+
+    fn is_used(&self, number: usize) -> bool {
+        self.bitmap[number / BITS_PER_WORD] & (1 << (number % BITS_PER_WORD)) != 0
+    }
+
+    fn mark_used(&mut self, number: usize) {
+        self.bitmap[number / BITS_PER_WORD] |= 1 << (number % BITS_PER_WORD);
+        self.free_count -= 1;
+    }
+
+    fn mark_free(&mut self, number: usize) {
+        self.bitmap[number / BITS_PER_WORD] &= !(1 << (number % BITS_PER_WORD));
+        self.free_count += 1;
+    }
+}
@@ -0,0 +1,195 @@
+use memory::Frame;
+
+pub const MAX_ORDER: usize = 10;
+// The pool is a single block of the largest order (4MiB).
+pub const POOL_FRAMES: usize = 1 << MAX_ORDER;
+const WORDS_PER_ORDER: usize = POOL_FRAMES / 64;
+
+/// Hands out physically contiguous blocks of `2^order` frames, each aligned to
+/// its own size, merging freed blocks back together with their buddies.
+pub struct BuddyAllocator {
+    base: usize,
+    // bit `i` of order `k` is set if the `i`th block of `2^k` frames is free
+    free: [[u64; WORDS_PER_ORDER]; MAX_ORDER + 1],
+}
+
+impl BuddyAllocator {
+    pub fn new(pool_start: Frame) -> BuddyAllocator {
+        assert!(
+            pool_start.number % POOL_FRAMES == 0,
+            "buddy pool must be aligned to its size"
+        );
+        let mut allocator = BuddyAllocator {
+            base: pool_start.number,
+            free: [[0; WORDS_PER_ORDER]; MAX_ORDER + 1],
+        };
+        allocator.set_free(MAX_ORDER, 0, true);
+        allocator
+    }
+
+    pub fn allocate_frames(&mut self, order: usize) -> Option<Frame> {
+        if order > MAX_ORDER {
+            return None;
+        }
+
+        let (mut current_order, mut index) = (order..MAX_ORDER + 1)
+            .filter_map(|k| self.first_free(k).map(|index| (k, index)))
+            .next()?;
+
+        self.set_free(current_order, index, false);
+        while current_order > order {
+            // keep the lower half and hand the upper half back as a free buddy
+            current_order -= 1;
+            index *= 2;
+            self.set_free(current_order, index + 1, true);
+        }
+
+        Some(Frame {
+            number: self.base + (index << order),
+        })
+    }
+
+    pub fn free_frames(&mut self, frame: Frame, order: usize) {
+        assert!(order <= MAX_ORDER, "order {} is too large", order);
+        assert!(
+            self.contains(&frame),
+            "frame {:#x} is not from the buddy pool",
+            frame.start_address()
+        );
+        let offset = frame.number - self.base;
+        assert!(
+            offset % (1 << order) == 0,
+            "block at {:#x} is not aligned to order {}",
+            frame.start_address(),
+            order
+        );
+
+        // a freed block might have been merged into a larger one since
+        assert!(
+            (order..MAX_ORDER + 1).all(|k| !self.is_free(k, offset >> k)),
+            "block at {:#x} freed twice",
+            frame.start_address()
+        );
+
+        let mut order = order;
+        let mut index = offset >> order;
+
+        while order < MAX_ORDER && self.is_free(order, index ^ 1) {
+            self.set_free(order, index ^ 1, false);
+            index /= 2;
+            order += 1;
+        }
+        self.set_free(order, index, true);
+    }
+
+    pub fn contains(&self, frame: &Frame) -> bool {
+        frame.number >= self.base && frame.number < self.base + POOL_FRAMES
+    }
+
+    pub fn free_frame_count(&self) -> usize {
+        self.free
+            .iter()
+            .enumerate()
+            .map(|(order, words)| {
+                let blocks: u32 = words.iter().map(|word| word.count_ones()).sum();
+                (blocks as usize) << order
+            })
+            .sum()
+    }
+
+    fn first_free(&self, order: usize) -> Option<usize> {
+        self.free[order]
+            .iter()
+            .position(|&word| word != 0)
+            .map(|i| i * 64 + self.free[order][i].trailing_zeros() as usize)
+    }
+
+    fn is_free(&self, order: usize, index: usize) -> bool {
+        self.free[order][index / 64] & (1 << (index % 64)) != 0
+    }
+
+    fn set_free(&mut self, order: usize, index: usize, free: bool) {
+        if free {
+            self.free[order][index / 64] |= 1 << (index % 64);
+        } else {
+            self.free[order][index / 64] &= !(1 << (index % 64));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BuddyAllocator, MAX_ORDER, POOL_FRAMES};
+    use memory::Frame;
+
+    fn allocator() -> BuddyAllocator {
+        BuddyAllocator::new(Frame {
+            number: POOL_FRAMES,
+        })
+    }
+
+    #[test]
+    fn split_hands_out_the_lowest_block() {
+        let mut allocator = allocator();
+        let first = allocator.allocate_frames(0).unwrap();
+        let second = allocator.allocate_frames(0).unwrap();
+        let pair = allocator.allocate_frames(1).unwrap();
+        assert_eq!(first.number, POOL_FRAMES);
+        assert_eq!(second.number, POOL_FRAMES + 1);
+        assert_eq!(pair.number, POOL_FRAMES + 2);
+        assert_eq!(allocator.free_frame_count(), POOL_FRAMES - 4);
+    }
+
+    #[test]
+    fn blocks_are_aligned_to_their_size() {
+        let mut allocator = allocator();
+        allocator.allocate_frames(0).unwrap();
+        let block = allocator.allocate_frames(3).unwrap();
+        assert_eq!(block.number, POOL_FRAMES + 8);
+    }
+
+    #[test]
+    fn freed_buddies_coalesce_up_to_the_top_order() {
+        let mut allocator = allocator();
+        let first = allocator.allocate_frames(0).unwrap();
+        let second = allocator.allocate_frames(0).unwrap();
+        allocator.free_frames(second, 0);
+        // the buddy of `first` is free, but `first` still isn't
+        assert!(allocator.allocate_frames(MAX_ORDER).is_none());
+
+        allocator.free_frames(first, 0);
+        assert_eq!(allocator.free_frame_count(), POOL_FRAMES);
+        let pool = allocator.allocate_frames(MAX_ORDER).unwrap();
+        assert_eq!(pool.number, POOL_FRAMES);
+    }
+
+    #[test]
+    fn exhausted_pool() {
+        let mut allocator = allocator();
+        let pool = allocator.allocate_frames(MAX_ORDER).unwrap();
+        assert!(allocator.allocate_frames(0).is_none());
+        assert!(allocator.allocate_frames(MAX_ORDER + 1).is_none());
+
+        allocator.free_frames(pool, MAX_ORDER);
+        assert_eq!(allocator.free_frame_count(), POOL_FRAMES);
+    }
+
+    #[test]
+    #[should_panic(expected = "freed twice")]
+    fn double_free() {
+        let mut allocator = allocator();
+        let first = allocator.allocate_frames(0).unwrap();
+        allocator.allocate_frames(0).unwrap();
+        allocator.free_frames(first.clone(), 0);
+        allocator.free_frames(first, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "freed twice")]
+    fn double_free_after_coalescing() {
+        let mut allocator = allocator();
+        let first = allocator.allocate_frames(0).unwrap();
+        allocator.free_frames(first.clone(), 0);
+        allocator.free_frames(first, 0);
+    }
+}
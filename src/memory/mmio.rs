@@ -0,0 +1,176 @@
+use core::ptr;
+use memory::paging::{self, ActivePageTable, EntryFlags, Page, PhysicalAddress, VirtualAddress};
+use memory::vma::{Permissions, Vma, VmaKind};
+use memory::{Frame, FrameAllocator, PAGE_SIZE};
+
+// A whole P4 entry.
+const MMIO_AREA_SIZE: usize = 512 * 512 * 512 * PAGE_SIZE;
+
+const IA32_PAT: u32 = 0x277;
+// The power-on default of the PAT, with entry 4 changed to write-combining.
+// Entries 0 to 3 keep their defaults, so pages without the PAT bit set are
+// unaffected.
+const PAT_VALUE: u64 = 0x0007_0401_0007_0406;
+// In a P1 entry bit 7 selects the upper half of the PAT instead of marking a
+// huge page, so with PWT and PCD clear it selects entry 4.
+const PAT_ENTRY_4: EntryFlags = paging::HUGE_PAGE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    Uncached,
+    WriteThrough,
+    /// Writes may be buffered and combined, but reads are never cached. Meant
+    /// for framebuffers. Falls back to `Uncached` without PAT support.
+    WriteCombining,
+}
+
+impl CacheMode {
+    fn flags(&self) -> EntryFlags {
+        match *self {
+            CacheMode::Uncached => paging::NO_CACHE | paging::WRITE_THROUGH,
+            CacheMode::WriteThrough => paging::WRITE_THROUGH,
+            CacheMode::WriteCombining if supports_pat() => PAT_ENTRY_4,
+            CacheMode::WriteCombining => CacheMode::Uncached.flags(),
+        }
+    }
+}
+
+/// Whether the CPU has a page attribute table.
+fn supports_pat() -> bool {
+    use core::arch::x86_64::__cpuid;
+
+    unsafe { __cpuid(1).edx & (1 << 16) != 0 }
+}
+
+/// Programs the PAT so `CacheMode::WriteCombining` can be used.
+pub fn init() {
+    use x86_64::instructions::tlb;
+    use x86_64::registers::msr::wrmsr;
+
+    if supports_pat() {
+        unsafe { wrmsr(IA32_PAT, PAT_VALUE) };
+        tlb::flush_all();
+    }
+}
+
+/// Maps the device memory at `physical_address..physical_address + size` to
+/// the lowest free pages of the MMIO area. Returns `None` if the area or the
+/// set of memory areas is full.
+pub fn map<A>(
+    active_table: &mut ActivePageTable,
+    physical_address: PhysicalAddress,
+    size: usize,
+    cache_mode: CacheMode,
+    allocator: &mut A,
+) -> Option<MmioRegion>
+where
+    A: FrameAllocator,
+{
+    use MMIO_START;
+
+    assert!(size > 0, "cannot map an empty MMIO region");
+
+    let start_frame = Frame::containing_address(physical_address);
+    let end_frame = Frame::containing_address(physical_address + size - 1);
+    let page_count = end_frame.number - start_frame.number + 1;
+
+    // the pages of unmapped regions are free again, since their areas are gone
+    let flags = paging::WRITABLE | paging::NO_EXECUTE | cache_mode.flags();
+    let (start_page, end_page) = {
+        let mut vmas = active_table.kernel_vmas();
+        let start_address =
+            vmas.find_free(page_count * PAGE_SIZE, MMIO_START, MMIO_START + MMIO_AREA_SIZE)?;
+        let end_address = start_address + page_count * PAGE_SIZE;
+        let vma = Vma::new(
+            start_address,
+            end_address,
+            Permissions::from_entry_flags(flags | paging::PRESENT),
+            VmaKind::Mmio,
+        );
+        vmas.insert(vma).ok()?;
+        (
+            Page::containing_address(start_address),
+            Page::containing_address(end_address - 1),
+        )
+    };
+
+    for (page, frame) in Page::range_inclusive(start_page, end_page)
+        .zip(Frame::range_inclusive(start_frame, end_frame))
+    {
+        active_table.map_to(page, frame, flags, allocator);
+    }
+
+    Some(MmioRegion {
+        start_page: start_page,
+        end_page: end_page,
+        virtual_address: start_page.start_address() + physical_address % PAGE_SIZE,
+        physical_address: physical_address,
+        size: size,
+    })
+}
+
+/// Device memory mapped by `MemoryController::map_mmio`, which is unmapped
+/// when the region is dropped.
+#[derive(Debug)]
+pub struct MmioRegion {
+    start_page: Page,
+    end_page: Page,
+    virtual_address: VirtualAddress,
+    physical_address: PhysicalAddress,
+    size: usize,
+}
+
+impl MmioRegion {
+    pub fn virtual_address(&self) -> VirtualAddress {
+        self.virtual_address
+    }
+
+    pub fn physical_address(&self) -> PhysicalAddress {
+        self.physical_address
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Reads the `T` at `offset` bytes into the region.
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        unsafe { ptr::read_volatile(self.address_of::<T>(offset) as *const T) }
+    }
+
+    /// Writes `value` to the `T` at `offset` bytes into the region.
+    pub fn write<T: Copy>(&mut self, offset: usize, value: T) {
+        unsafe { ptr::write_volatile(self.address_of::<T>(offset) as *mut T, value) }
+    }
+
+    fn address_of<T>(&self, offset: usize) -> VirtualAddress {
+        use core::mem::{align_of, size_of};
+
+        assert!(
+            offset + size_of::<T>() <= self.size,
+            "offset {:#x} is outside the MMIO region",
+            offset
+        );
+        let address = self.virtual_address + offset;
+        assert!(address % align_of::<T>() == 0, "unaligned MMIO access");
+        address
+    }
+}
+
+impl Drop for MmioRegion {
+    fn drop(&mut self) {
+        use memory::paging::Mapper;
+        use memory::vma;
+        use memory::GlobalFrameAllocator;
+
+        // the MMIO area is part of the kernel half, which is the same in
+        // every address space
+        let mut mapper = unsafe { Mapper::new() };
+        for page in Page::range_inclusive(self.start_page, self.end_page) {
+            // the frames belong to the device, so they aren't freed
+            mapper.unmap(page, &mut GlobalFrameAllocator);
+        }
+        // the pages can be used by `map` again
+        vma::KERNEL.lock().remove(self.start_page.start_address());
+    }
+}
@@ -1,17 +1,32 @@
 #![feature(ptr_internals)]
 
-pub use self::area_frame_allocator::AreaFrameAllocator;
-pub use self::paging::remap_the_kernel;
-use self::paging::PhysicalAddress;
-pub use self::stack_allocator::Stack;
+pub use self::bitmap_frame_allocator::BitmapFrameAllocator;
+pub use self::buddy_allocator::BuddyAllocator;
+pub use self::mmio::{CacheMode, MmioRegion};
+pub use self::paging::{remap_the_kernel, InactivePageTable};
+use self::paging::{PhysicalAddress, VirtualAddress};
+pub use self::stack_allocator::{overflowed_stack, Stack, StackBounds};
+pub use self::vma::{Permissions, Vma, VmaKind};
 use allocator;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use multiboot2::BootInformation;
+use spin::Mutex;
 
-mod area_frame_allocator;
+mod bitmap_frame_allocator;
+mod buddy_allocator;
+mod mmio;
 mod paging;
 mod stack_allocator;
+mod vma;
 
 pub const PAGE_SIZE: usize = 4096;
+const STACK_AREA_PAGES: usize = 100;
+const HEAP_GROWTH_STEP: usize = 16 * PAGE_SIZE;
+
+static FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+// The end of the part of the heap handed to the allocator so far. Faults
+// above it are bugs rather than heap accesses.
+static HEAP_END: AtomicUsize = AtomicUsize::new(0);
 
 pub fn init(boot_info: &BootInformation) -> MemoryController {
     assert_has_not_been_called!("`memory::init` must be called only once");
@@ -21,16 +36,17 @@ pub fn init(boot_info: &BootInformation) -> MemoryController {
         .elf_sections_tag()
         .expect("Elf sections tag required");
 
+    // physical addresses, most sections are linked into the higher half
     let kernel_start = elf_sections_tag
         .sections()
         .filter(|s| s.is_allocated())
-        .map(|s| s.start_address())
+        .map(|s| paging::kernel_physical_address(s.start_address() as usize))
         .min()
         .unwrap();
     let kernel_end = elf_sections_tag
         .sections()
         .filter(|s| s.is_allocated())
-        .map(|s| s.end_address())
+        .map(|s| paging::kernel_physical_address(s.end_address() as usize))
         .max()
         .unwrap();
 
@@ -44,41 +60,233 @@ pub fn init(boot_info: &BootInformation) -> MemoryController {
         boot_info.end_address()
     );
 
-    let mut frame_allocator = AreaFrameAllocator::new(
-        kernel_start as usize,
-        kernel_end as usize,
-        boot_info.start_address(),
-        boot_info.end_address(),
-        memory_map_tag.memory_areas(),
+    let mut frame_allocator = BitmapFrameAllocator::new(memory_map_tag.memory_areas());
+    frame_allocator.reserve_range(kernel_start, kernel_end);
+    frame_allocator.reserve_range(
+        paging::kernel_physical_address(boot_info.start_address()),
+        paging::kernel_physical_address(boot_info.end_address()),
     );
+    for module in boot_info.module_tags() {
+        println!(
+            "module start: {:#x}, module end: {:#x}",
+            module.start_address(),
+            module.end_address()
+        );
+        frame_allocator.reserve_range(
+            module.start_address() as usize,
+            module.end_address() as usize,
+        );
+    }
 
-    let mut active_table = paging::remap_the_kernel(&mut frame_allocator, boot_info);
+    println!(
+        "{} of {} frames free",
+        frame_allocator.free_frame_count(),
+        frame_allocator.total_frame_count()
+    );
 
-    use self::paging::Page;
-    use super::{HEAP_SIZE, HEAP_START};
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+    let mut frame_allocator = GlobalFrameAllocator;
 
-    let heap_start_page = Page::containing_address(HEAP_START);
-    let heap_end_page = Page::containing_address(HEAP_START + HEAP_SIZE - 1);
+    let mut active_table = paging::remap_the_kernel(&mut frame_allocator, boot_info);
 
-    for page in Page::range_inclusive(heap_start_page, heap_end_page) {
-        active_table.map(page, paging::WRITABLE, &mut frame_allocator);
-    }
+    let physical_memory_end = paging::map_physical_memory(
+        &mut active_table,
+        memory_map_tag.memory_areas(),
+        &mut frame_allocator,
+    );
+    paging::preallocate_kernel_tables(&mut active_table, &mut frame_allocator);
 
-    unsafe {
-        allocator::init(HEAP_START, HEAP_SIZE);
+    {
+        use super::{HEAP_MAX_SIZE, HEAP_START, PHYSICAL_MEMORY_OFFSET};
+
+        let mut vmas = active_table.kernel_vmas();
+        vmas.insert(Vma::new(
+            PHYSICAL_MEMORY_OFFSET,
+            PHYSICAL_MEMORY_OFFSET + physical_memory_end,
+            Permissions::KERNEL_DATA,
+            VmaKind::PhysicalMemory,
+        ))
+        .expect("too many memory areas");
+        // all of it, pages are only mapped as the heap grows
+        vmas.insert(Vma::new(
+            HEAP_START,
+            HEAP_START + HEAP_MAX_SIZE,
+            Permissions::KERNEL_DATA,
+            VmaKind::Heap,
+        ))
+        .expect("too many memory areas");
     }
 
-    let stack_allocator = {
-        let stack_alloc_start = heap_end_page + 1;
-        let stack_alloc_end = stack_alloc_start + 100;
-        let stack_alloc_range = Page::range_inclusive(stack_alloc_start, stack_alloc_end);
-        stack_allocator::StackAllocator::new(stack_alloc_range)
+    let buddy_allocator = {
+        let pool_size = buddy_allocator::POOL_FRAMES;
+        let pool_start = GlobalFrameAllocator::with(|allocator| {
+            allocator.allocate_contiguous(pool_size, pool_size)
+        }).expect("no memory for the buddy allocator pool");
+        BuddyAllocator::new(pool_start)
     };
 
+    let stack_allocator = stack_allocator::StackAllocator::new(stack_area());
+
+    mmio::init();
+
     MemoryController {
         active_table: active_table,
         frame_allocator: frame_allocator,
+        buddy_allocator: buddy_allocator,
         stack_allocator: stack_allocator,
+        physical_memory_end: physical_memory_end,
+    }
+}
+
+/// Sets up the kernel heap. Heap pages are only backed by frames once they
+/// are first touched, so this must be called after the page fault handler
+/// has been installed.
+pub fn init_heap() {
+    use super::{HEAP_INITIAL_SIZE, HEAP_START};
+
+    assert_has_not_been_called!("`memory::init_heap` must be called only once");
+
+    allocator::set_grow_handler(grow_heap);
+    HEAP_END.store(HEAP_START + HEAP_INITIAL_SIZE, Ordering::SeqCst);
+    unsafe {
+        allocator::init(HEAP_START, HEAP_INITIAL_SIZE);
+    }
+}
+
+/// Maps at least `min_size` more bytes onto the end of the heap at
+/// `heap_top`, without going past `HEAP_MAX_SIZE`. Returns the number of
+/// bytes added.
+fn grow_heap(heap_top: usize, min_size: usize) -> usize {
+    use self::paging::Page;
+    use super::{HEAP_MAX_SIZE, HEAP_START};
+    use core::cmp;
+
+    assert!(heap_top % PAGE_SIZE == 0, "heap must end on a page boundary");
+
+    let step = cmp::max(min_size, HEAP_GROWTH_STEP);
+    let size = cmp::min(
+        (step + PAGE_SIZE - 1) & !(PAGE_SIZE - 1),
+        HEAP_START + HEAP_MAX_SIZE - heap_top,
+    );
+    if size < min_size {
+        return 0;
+    }
+
+    // map the pages now, so running out of frames fails the allocation rather
+    // than panicking in the page fault handler later on
+    let start_page = Page::containing_address(heap_top);
+    let end_page = Page::containing_address(heap_top + size - 1);
+    let mut added = size;
+    for page in Page::range_inclusive(start_page, end_page) {
+        if !back_heap_page(page.start_address()) {
+            added = page.start_address() - heap_top;
+            break;
+        }
+    }
+    HEAP_END.store(heap_top + added, Ordering::SeqCst);
+    added
+}
+
+/// Backs the heap page containing `address` with a fresh frame, returning
+/// false if `address` isn't below the current end of the heap, is already
+/// mapped or there are no frames left.
+pub fn map_heap_page(address: usize) -> bool {
+    use super::HEAP_START;
+
+    if address < HEAP_START || address >= HEAP_END.load(Ordering::SeqCst) {
+        return false;
+    }
+    back_heap_page(address)
+}
+
+fn back_heap_page(address: usize) -> bool {
+    use self::paging::{Mapper, Page};
+    use x86_64::registers::control_regs;
+
+    let mut mapper = unsafe { Mapper::new() };
+    let page = Page::containing_address(address);
+    if mapper.translate_page(page).is_some() {
+        return false;
+    }
+
+    // mapping through the recursive entry while `ActivePageTable::with` has it
+    // pointed at another table would put the page in the wrong address space
+    let active_p4_frame = Frame::containing_address(control_regs::cr3().0 as usize);
+    assert!(
+        mapper.p4()[paging::RECURSIVE_INDEX].pointed_frame() == Some(active_p4_frame),
+        "heap page fault while editing an inactive page table"
+    );
+
+    // touching a lazily mapped heap page with the frame allocator locked
+    // would spin forever below
+    let mut frame_allocator = match FRAME_ALLOCATOR.try_lock() {
+        Some(frame_allocator) => frame_allocator,
+        None => panic!(
+            "heap page {:#x} touched while the frame allocator is locked",
+            page.start_address()
+        ),
+    };
+    let frame_allocator = frame_allocator
+        .as_mut()
+        .expect("frame allocator not initialised");
+    match frame_allocator.allocate_frame() {
+        Some(frame) => {
+            mapper.map_to(page, frame, paging::WRITABLE, frame_allocator);
+            true
+        }
+        None => false,
+    }
+}
+
+/// The pages handed out by the stack allocator, directly after the heap.
+fn stack_area() -> paging::PageIter {
+    use self::paging::Page;
+    use super::{HEAP_MAX_SIZE, HEAP_START};
+
+    let stack_area_start = Page::containing_address(HEAP_START + HEAP_MAX_SIZE - 1) + 1;
+    let stack_area_end = stack_area_start + STACK_AREA_PAGES;
+    Page::range_inclusive(stack_area_start, stack_area_end)
+}
+
+/// Looks `address` up in the active address space, returning the memory area
+/// it is part of and the physical address it is mapped to, if any. Intended
+/// for fault diagnostics, where the `MemoryController` isn't reachable.
+pub fn describe_address(address: usize) -> (Option<Vma>, Option<PhysicalAddress>) {
+    use self::paging::Mapper;
+
+    let physical_address = unsafe { Mapper::new() }.translate(address);
+    // the areas might be locked by whatever the fault interrupted
+    let vma = [&vma::KERNEL, &vma::ACTIVE]
+        .iter()
+        .filter_map(|vmas| vmas.try_lock())
+        .filter_map(|vmas| vmas.find(address).cloned())
+        .next();
+
+    (vma, physical_address)
+}
+
+/// Gives the active address space its own writable copy of the copy-on-write
+/// page containing `address`. Returns false if the page isn't copy-on-write or
+/// there are no frames left.
+pub fn resolve_cow_fault(address: usize) -> bool {
+    use self::paging::{Mapper, Page};
+
+    let mut mapper = unsafe { Mapper::new() };
+    paging::resolve_write_fault(
+        &mut mapper,
+        Page::containing_address(address),
+        &mut GlobalFrameAllocator,
+    )
+}
+
+/// Prints the memory areas of the active address space, one per line, in the
+/// style of `/proc/<pid>/maps`.
+pub fn print_maps() {
+    for vma in vma::ACTIVE.lock().iter() {
+        println!("{}", vma);
+    }
+    for vma in vma::KERNEL.lock().iter() {
+        println!("{}", vma);
     }
 }
 
@@ -94,7 +302,7 @@ impl Frame {
         }
     }
 
-    fn start_address(&self) -> PhysicalAddress {
+    pub fn start_address(&self) -> PhysicalAddress {
         self.number * PAGE_SIZE
     }
 
@@ -136,10 +344,49 @@ pub trait FrameAllocator {
     fn deallocate_frame(&mut self, frame: Frame);
 }
 
+/// A handle to the kernel's frame allocator. The allocator itself lives in a
+/// static so that the page fault handler can allocate frames too.
+pub struct GlobalFrameAllocator;
+
+impl GlobalFrameAllocator {
+    fn with<F, T>(f: F) -> T
+    where
+        F: FnOnce(&mut BitmapFrameAllocator) -> T,
+    {
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        f(frame_allocator
+            .as_mut()
+            .expect("frame allocator not initialised"))
+    }
+}
+
+impl GlobalFrameAllocator {
+    pub fn share_frame(&mut self, frame: &Frame) {
+        GlobalFrameAllocator::with(|allocator| allocator.share_frame(frame))
+    }
+
+    pub fn reference_count(&self, frame: &Frame) -> usize {
+        GlobalFrameAllocator::with(|allocator| allocator.reference_count(frame))
+    }
+}
+
+impl FrameAllocator for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        GlobalFrameAllocator::with(|allocator| allocator.allocate_frame())
+    }
+
+    fn deallocate_frame(&mut self, frame: Frame) {
+        GlobalFrameAllocator::with(|allocator| allocator.deallocate_frame(frame))
+    }
+}
+
 pub struct MemoryController {
     active_table: paging::ActivePageTable,
-    frame_allocator: AreaFrameAllocator,
+    frame_allocator: GlobalFrameAllocator,
+    buddy_allocator: BuddyAllocator,
     stack_allocator: stack_allocator::StackAllocator,
+    // the end of the physical memory mapped at PHYSICAL_MEMORY_OFFSET
+    physical_memory_end: PhysicalAddress,
 }
 
 impl MemoryController {
@@ -148,7 +395,161 @@ impl MemoryController {
             ref mut active_table,
             ref mut frame_allocator,
             ref mut stack_allocator,
+            ..
         } = self;
         stack_allocator.alloc_stack(active_table, frame_allocator, size_in_pages)
     }
+
+    /// Unmaps `stack` and frees its frames. Its pages are reused by later
+    /// `alloc_stack` calls.
+    pub fn free_stack(&mut self, stack: Stack) {
+        let &mut MemoryController {
+            ref mut active_table,
+            ref mut frame_allocator,
+            ref mut stack_allocator,
+            ..
+        } = self;
+        stack_allocator.free_stack(stack, active_table, frame_allocator)
+    }
+
+    /// Allocates `count` physically contiguous frames aligned to `align` frames,
+    /// e.g. for DMA buffers.
+    pub fn alloc_contiguous_frames(&mut self, count: usize, align: usize) -> Option<Frame> {
+        GlobalFrameAllocator::with(|allocator| allocator.allocate_contiguous(count, align))
+    }
+
+    pub fn free_contiguous_frames(&mut self, first: Frame, count: usize) {
+        GlobalFrameAllocator::with(|allocator| allocator.deallocate_contiguous(first, count))
+    }
+
+    /// Allocates a block of `2^order` physically contiguous frames, aligned to
+    /// the size of the block.
+    pub fn alloc_frames(&mut self, order: usize) -> Option<Frame> {
+        self.buddy_allocator.allocate_frames(order)
+    }
+
+    pub fn free_frames(&mut self, first: Frame, order: usize) {
+        self.buddy_allocator.free_frames(first, order)
+    }
+
+    /// Creates an address space with nothing mapped in its user half.
+    pub fn new_address_space(&mut self) -> InactivePageTable {
+        let &mut MemoryController {
+            ref mut active_table,
+            ref mut frame_allocator,
+            ..
+        } = self;
+        InactivePageTable::new(active_table, frame_allocator)
+    }
+
+    /// See `ActivePageTable::switch`.
+    pub fn switch_address_space(&mut self, table: InactivePageTable) -> InactivePageTable {
+        self.active_table.switch(table)
+    }
+
+    /// See `ActivePageTable::clone_cow`.
+    pub fn clone_address_space(&mut self) -> InactivePageTable {
+        let &mut MemoryController {
+            ref mut active_table,
+            ref mut frame_allocator,
+            ..
+        } = self;
+        active_table.clone_cow(frame_allocator)
+    }
+
+    /// Maps `size` bytes of device memory starting at `physical_address` with
+    /// the given caching. The mapping lasts until the returned region is
+    /// dropped. Returns `None` if there's no room for it.
+    pub fn map_mmio(
+        &mut self,
+        physical_address: PhysicalAddress,
+        size: usize,
+        cache_mode: CacheMode,
+    ) -> Option<MmioRegion> {
+        let &mut MemoryController {
+            ref mut active_table,
+            ref mut frame_allocator,
+            ..
+        } = self;
+        mmio::map(active_table, physical_address, size, cache_mode, frame_allocator)
+    }
+
+    /// Whether `physical_address..physical_address + size` is part of the
+    /// direct map of physical memory.
+    pub fn is_directly_mapped(&self, physical_address: PhysicalAddress, size: usize) -> bool {
+        physical_address <= self.physical_memory_end
+            && self.physical_memory_end - physical_address >= size
+    }
+
+    /// The address `physical_address` is mapped at in the direct map of
+    /// physical memory.
+    pub fn phys_to_virt(&self, physical_address: PhysicalAddress) -> VirtualAddress {
+        use super::PHYSICAL_MEMORY_OFFSET;
+
+        assert!(
+            physical_address < self.physical_memory_end,
+            "{:#x} is not part of the direct map",
+            physical_address
+        );
+        PHYSICAL_MEMORY_OFFSET + physical_address
+    }
+
+    pub fn virt_to_phys(&self, virtual_address: VirtualAddress) -> Option<PhysicalAddress> {
+        use super::PHYSICAL_MEMORY_OFFSET;
+
+        if virtual_address >= PHYSICAL_MEMORY_OFFSET
+            && virtual_address < PHYSICAL_MEMORY_OFFSET + self.physical_memory_end
+        {
+            Some(virtual_address - PHYSICAL_MEMORY_OFFSET)
+        } else {
+            self.active_table.translate(virtual_address)
+        }
+    }
+
+    pub fn free_frame_count(&self) -> usize {
+        GlobalFrameAllocator::with(|allocator| allocator.free_frame_count())
+    }
+
+    pub fn used_frame_count(&self) -> usize {
+        GlobalFrameAllocator::with(|allocator| allocator.used_frame_count())
+    }
+
+    /// Prints a summary of frame and heap usage.
+    pub fn print_meminfo(&self) {
+        println!(
+            "frames: {} used, {} free, {} free in the buddy pool",
+            self.used_frame_count(),
+            self.free_frame_count(),
+            self.buddy_allocator.free_frame_count()
+        );
+        print_heap_stats();
+        for cache in allocator::slab_stats().iter() {
+            println!(
+                "slab {:>4}: {} in use, {} free, {} slabs",
+                cache.object_size, cache.objects_in_use, cache.free_objects, cache.slabs
+            );
+        }
+    }
+}
+
+/// Prints the kernel heap's usage. Doesn't allocate, so it is safe to call
+/// when the heap is exhausted.
+pub fn print_heap_stats() {
+    let heap = allocator::stats();
+
+    println!(
+        "heap: {} of {} bytes in use, peak {}, {} allocs, {} frees",
+        heap.bytes_in_use,
+        heap.heap_size,
+        heap.peak_bytes_in_use,
+        heap.allocations,
+        heap.frees
+    );
+    println!(
+        "holes: {} bytes in {}, largest {}, {}% fragmented",
+        heap.free_bytes,
+        heap.hole_count,
+        heap.largest_free_hole,
+        heap.fragmentation()
+    );
 }
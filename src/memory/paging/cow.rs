@@ -0,0 +1,113 @@
+use super::entry::*;
+use super::table::entries;
+use super::{Mapper, Page, KERNEL_P4_START, RECURSIVE_INDEX};
+use core::ptr;
+use memory::{Frame, FrameAllocator, GlobalFrameAllocator, PAGE_SIZE};
+use x86_64::instructions::tlb;
+use x86_64::VirtualAddress;
+
+/// Creates a copy of the address space with the P4 table in `source_p4`. The
+/// kernel half is shared, while every user frame ends up mapped read-only in
+/// both address spaces, so whichever writes to it first gets its own copy.
+///
+/// The source entries are changed too, so the caller has to flush the TLB if
+/// `source_p4` is active.
+pub fn clone_address_space(source_p4: &Frame, allocator: &mut GlobalFrameAllocator) -> Frame {
+    let p4_frame = allocator.allocate_frame().expect("out of memory");
+    let (source, p4) = unsafe { (entries(source_p4), entries(&p4_frame)) };
+
+    for (index, (source_entry, entry)) in source.iter_mut().zip(p4.iter_mut()).enumerate() {
+        entry.set_unused();
+        let frame = match source_entry.pointed_frame() {
+            Some(frame) => frame,
+            None => continue,
+        };
+        if index == RECURSIVE_INDEX {
+            entry.set(p4_frame.clone(), PRESENT | WRITABLE);
+        } else if index >= KERNEL_P4_START {
+            entry.set(frame, source_entry.flags());
+        } else {
+            entry.set(clone_table(&frame, 3, allocator), source_entry.flags());
+        }
+    }
+    p4_frame
+}
+
+/// Copies the P`level` table in `source` and everything below it into new
+/// tables, sharing the frames they map copy-on-write.
+fn clone_table(source: &Frame, level: usize, allocator: &mut GlobalFrameAllocator) -> Frame {
+    let table_frame = allocator.allocate_frame().expect("out of memory");
+    let (source, table) = unsafe { (entries(source), entries(&table_frame)) };
+
+    for (source_entry, entry) in source.iter_mut().zip(table.iter_mut()) {
+        entry.set_unused();
+        let frame = match source_entry.pointed_frame() {
+            Some(frame) => frame,
+            None => continue,
+        };
+        let flags = source_entry.flags();
+
+        if level == 1 {
+            let flags = if flags.contains(WRITABLE) {
+                (flags - WRITABLE) | COPY_ON_WRITE
+            } else {
+                flags
+            };
+            allocator.share_frame(&frame);
+            source_entry.set(frame.clone(), flags);
+            entry.set(frame, flags);
+        } else {
+            assert!(
+                !flags.contains(HUGE_PAGE),
+                "huge pages can't be shared copy-on-write"
+            );
+            entry.set(clone_table(&frame, level - 1, allocator), flags);
+        }
+    }
+    table_frame
+}
+
+/// Handles a write to the copy-on-write `page` in the active address space by
+/// making it writable again, copying its frame first if it is still shared.
+/// Returns false if `page` isn't copy-on-write or no frame is left for the
+/// copy.
+pub fn resolve_write_fault(
+    mapper: &mut Mapper,
+    page: Page,
+    allocator: &mut GlobalFrameAllocator,
+) -> bool {
+    use PHYSICAL_MEMORY_OFFSET;
+
+    let entry = match mapper.p1_entry_mut(page) {
+        Some(entry) => entry,
+        None => return false,
+    };
+    let flags = entry.flags();
+    if !flags.contains(PRESENT | COPY_ON_WRITE) {
+        return false;
+    }
+    let frame = entry.pointed_frame().unwrap();
+    let flags = (flags - COPY_ON_WRITE) | WRITABLE;
+
+    if allocator.reference_count(&frame) == 1 {
+        // every other address space already made its own copy
+        entry.set(frame, flags);
+    } else {
+        let copy = match allocator.allocate_frame() {
+            Some(copy) => copy,
+            None => return false,
+        };
+        unsafe {
+            ptr::copy_nonoverlapping(
+                (PHYSICAL_MEMORY_OFFSET + frame.start_address()) as *const u8,
+                (PHYSICAL_MEMORY_OFFSET + copy.start_address()) as *mut u8,
+                PAGE_SIZE,
+            );
+        }
+        entry.set(copy, flags);
+        allocator.deallocate_frame(frame);
+    }
+
+    tlb::flush(VirtualAddress(page.start_address()));
+    true
+}
@@ -3,6 +3,10 @@ use multiboot2::ElfSection;
 
 pub struct Entry(u64);
 
+// In a P2 or P3 entry with HUGE_PAGE set, bit 12 selects the upper half of the
+// PAT. It sits inside the frame address of normal entries.
+const HUGE_PAGE_PAT: u64 = 1 << 12;
+
 impl Entry {
     pub fn is_unused(&self) -> bool {
         self.0 == 0
@@ -26,6 +30,11 @@ impl Entry {
         }
     }
 
+    // only meaningful for P2 and P3 entries, see `HUGE_PAGE_PAT`
+    pub fn huge_page_pat(&self) -> bool {
+        self.flags().contains(HUGE_PAGE) && self.0 & HUGE_PAGE_PAT != 0
+    }
+
     pub fn set(&mut self, frame: Frame, flags: EntryFlags) {
         assert!(frame.start_address() & !0x000fffff_fffff000 == 0);
         self.0 = (frame.start_address() as u64) | flags.bits();
@@ -43,6 +52,8 @@ bitflags! {
         const DIRTY           = 1 <<  6;
         const HUGE_PAGE       = 1 <<  7;
         const GLOBAL          = 1 <<  8;
+        // bits 9 to 11 are free for the kernel to use
+        const COPY_ON_WRITE   = 1 <<  9;
         const NO_EXECUTE      = 1 << 63;
     }
 }
@@ -2,7 +2,7 @@
 
 use super::entry::*;
 use super::table::{self, Level1, Level4, Table};
-use super::{Page, PhysicalAddress, VirtualAddress, ENTRY_COUNT};
+use super::{HugePageSize, Page, PhysicalAddress, VirtualAddress, ENTRY_COUNT, KERNEL_P4_START};
 use core::ptr::Unique;
 use memory::{Frame, FrameAllocator, PAGE_SIZE};
 
@@ -85,6 +85,45 @@ impl Mapper {
         p1[page.p1_index()].set(frame, flags | PRESENT);
     }
 
+    /// Maps a huge page starting at `page` to the equally sized block of frames
+    /// starting at `frame`. Both must be aligned to the huge page size.
+    pub fn map_to_huge<A>(
+        &mut self,
+        page: Page,
+        frame: Frame,
+        size: HugePageSize,
+        flags: EntryFlags,
+        allocator: &mut A,
+    ) where
+        A: FrameAllocator,
+    {
+        assert!(
+            page.number % size.frame_count() == 0,
+            "huge page must be aligned to its size"
+        );
+        assert!(
+            frame.number % size.frame_count() == 0,
+            "huge frame must be aligned to its size"
+        );
+
+        let p3 = self.p4_mut().next_table_create(page.p4_index(), allocator);
+        match size {
+            HugePageSize::OneGiB => {
+                assert!(
+                    super::supports_1gib_pages(),
+                    "1GiB pages are not supported by this CPU"
+                );
+                assert!(p3[page.p3_index()].is_unused());
+                p3[page.p3_index()].set(frame, flags | PRESENT | HUGE_PAGE);
+            }
+            HugePageSize::TwoMiB => {
+                let p2 = p3.next_table_create(page.p3_index(), allocator);
+                assert!(p2[page.p2_index()].is_unused());
+                p2[page.p2_index()].set(frame, flags | PRESENT | HUGE_PAGE);
+            }
+        }
+    }
+
     pub fn map<A>(&mut self, page: Page, flags: EntryFlags, allocator: &mut A)
     where
         A: FrameAllocator,
@@ -101,7 +140,10 @@ impl Mapper {
         self.map_to(page, frame, flags, allocator)
     }
 
-    pub fn unmap<A>(&mut self, page: Page, allocator: &mut A)
+    /// Unmaps `page`, freeing any page tables left empty, and returns the frame
+    /// it was mapped to. The frame itself is left to the caller. If `page` is
+    /// part of a huge page, the huge page is split first.
+    pub fn unmap<A>(&mut self, page: Page, allocator: &mut A) -> Frame
     where
         A: FrameAllocator,
     {
@@ -110,16 +152,185 @@ impl Mapper {
 
         assert!(self.translate(page.start_address()).is_some());
 
-        let p1 = self.p4_mut()
+        while self.huge_page_size(page).is_some() {
+            self.split_huge_page(page, allocator);
+        }
+
+        let frame = {
+            let p1 = self.p4_mut()
+                .next_table_mut(page.p4_index())
+                .and_then(|p3| p3.next_table_mut(page.p3_index()))
+                .and_then(|p2| p2.next_table_mut(page.p2_index()))
+                .unwrap();
+            let frame = p1[page.p1_index()].pointed_frame().unwrap();
+            p1[page.p1_index()].set_unused();
+            frame
+        };
+
+        tlb::flush(VirtualAddress(page.start_address()));
+        self.free_empty_tables(page, allocator);
+        frame
+    }
+
+    /// Unmaps `page` and returns its frame to the allocator.
+    pub fn unmap_and_free<A>(&mut self, page: Page, allocator: &mut A)
+    where
+        A: FrameAllocator,
+    {
+        let frame = self.unmap(page, allocator);
+        allocator.deallocate_frame(frame);
+    }
+
+    /// Unmaps the huge page starting at `page` and returns its first frame.
+    pub fn unmap_huge<A>(&mut self, page: Page, size: HugePageSize, allocator: &mut A) -> Frame
+    where
+        A: FrameAllocator,
+    {
+        use x86_64::instructions::tlb;
+        use x86_64::VirtualAddress;
+
+        assert!(
+            self.huge_page_size(page) == Some(size),
+            "page {:#x} is not mapped as a {:?} page",
+            page.start_address(),
+            size
+        );
+        assert!(
+            page.number % size.frame_count() == 0,
+            "huge page must be aligned to its size"
+        );
+
+        let frame = {
+            let p3 = self.p4_mut().next_table_mut(page.p4_index()).unwrap();
+            match size {
+                HugePageSize::OneGiB => {
+                    let frame = p3[page.p3_index()].pointed_frame().unwrap();
+                    p3[page.p3_index()].set_unused();
+                    frame
+                }
+                HugePageSize::TwoMiB => {
+                    let p2 = p3.next_table_mut(page.p3_index()).unwrap();
+                    let frame = p2[page.p2_index()].pointed_frame().unwrap();
+                    p2[page.p2_index()].set_unused();
+                    frame
+                }
+            }
+        };
+
+        tlb::flush(VirtualAddress(page.start_address()));
+        self.free_empty_tables(page, allocator);
+        frame
+    }
+
+    /// The P1 entry for `page`, if `page` isn't part of a huge page and its P1
+    /// table exists.
+    pub fn p1_entry_mut(&mut self, page: Page) -> Option<&mut Entry> {
+        self.p4_mut()
             .next_table_mut(page.p4_index())
             .and_then(|p3| p3.next_table_mut(page.p3_index()))
             .and_then(|p2| p2.next_table_mut(page.p2_index()))
-            .expect("Mapping huge pages is unsupported in huOS");
-        let frame = p1[page.p1_index()].pointed_frame().unwrap();
-        p1[page.p1_index()].set_unused();
+            .map(|p1| &mut p1[page.p1_index()])
+    }
 
-        tlb::flush(VirtualAddress(page.start_address()));
-        // TODO free p(1, 2, 3) tables if empty.
-        // allocator.deallocate_frame(frame);
+    /// Returns the size of the huge page that `page` is part of, if any.
+    pub fn huge_page_size(&self, page: Page) -> Option<HugePageSize> {
+        let p3 = self.p4().next_table(page.p4_index())?;
+        if p3[page.p3_index()].flags().contains(PRESENT | HUGE_PAGE) {
+            return Some(HugePageSize::OneGiB);
+        }
+        let p2 = p3.next_table(page.p3_index())?;
+        if p2[page.p2_index()].flags().contains(PRESENT | HUGE_PAGE) {
+            return Some(HugePageSize::TwoMiB);
+        }
+        None
+    }
+
+    /// Replaces the huge page containing `page` with a table of pages one size
+    /// smaller, mapping the same frames with the same flags.
+    pub fn split_huge_page<A>(&mut self, page: Page, allocator: &mut A)
+    where
+        A: FrameAllocator,
+    {
+        use x86_64::instructions::tlb;
+        use x86_64::VirtualAddress;
+
+        // the new table must allow everything the huge page did, the leaf
+        // entries still carry the real restrictions
+        let table_flags = |flags: EntryFlags| PRESENT | WRITABLE | (flags & USER_ACCESSIBLE);
+
+        let size = self.huge_page_size(page).expect("page is not part of a huge page");
+        let table_frame = allocator.allocate_frame().expect("no frames available");
+
+        {
+            let p3 = self.p4_mut().next_table_mut(page.p4_index()).unwrap();
+            match size {
+                HugePageSize::OneGiB => {
+                    assert!(
+                        !p3[page.p3_index()].huge_page_pat(),
+                        "splitting huge pages with the PAT bit set is not supported"
+                    );
+                    let start_frame = p3[page.p3_index()].pointed_frame().unwrap();
+                    let flags = p3[page.p3_index()].flags();
+                    p3[page.p3_index()].set(table_frame, table_flags(flags));
+
+                    // the recursive address of the new table used to be part
+                    // of the huge page
+                    let p2 = p3.next_table_mut(page.p3_index()).unwrap();
+                    tlb::flush(VirtualAddress(p2 as *mut _ as usize));
+                    for i in 0..ENTRY_COUNT {
+                        let frame = Frame {
+                            number: start_frame.number + i * ENTRY_COUNT,
+                        };
+                        p2[i].set(frame, flags);
+                    }
+                }
+                HugePageSize::TwoMiB => {
+                    let p2 = p3.next_table_mut(page.p3_index()).unwrap();
+                    assert!(
+                        !p2[page.p2_index()].huge_page_pat(),
+                        "splitting huge pages with the PAT bit set is not supported"
+                    );
+                    let start_frame = p2[page.p2_index()].pointed_frame().unwrap();
+                    let flags = p2[page.p2_index()].flags();
+                    p2[page.p2_index()].set(table_frame, table_flags(flags));
+
+                    // bit 7 means PAT rather than HUGE_PAGE in a P1 entry
+                    let p1 = p2.next_table_mut(page.p2_index()).unwrap();
+                    tlb::flush(VirtualAddress(p1 as *mut _ as usize));
+                    for i in 0..ENTRY_COUNT {
+                        let frame = Frame {
+                            number: start_frame.number + i,
+                        };
+                        p1[i].set(frame, flags - HUGE_PAGE);
+                    }
+                }
+            }
+        }
+
+        tlb::flush_all();
+    }
+
+    /// Walks back up the hierarchy from `page` freeing any tables that have
+    /// become empty.
+    fn free_empty_tables<A>(&mut self, page: Page, allocator: &mut A)
+    where
+        A: FrameAllocator,
+    {
+        let p4 = self.p4_mut();
+        {
+            let p3 = match p4.next_table_mut(page.p4_index()) {
+                Some(p3) => p3,
+                None => return,
+            };
+            if let Some(p2) = p3.next_table_mut(page.p3_index()) {
+                p2.free_next_table_if_empty(page.p2_index(), allocator);
+            }
+            p3.free_next_table_if_empty(page.p3_index(), allocator);
+        }
+        // the kernel P3 tables are shared by every address space, see
+        // `preallocate_kernel_tables`
+        if page.p4_index() < KERNEL_P4_START {
+            p4.free_next_table_if_empty(page.p4_index(), allocator);
+        }
     }
 }
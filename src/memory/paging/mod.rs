@@ -1,24 +1,35 @@
 #![feature(ptr_internals)]
 
 pub use self::entry::*;
+pub use self::cow::resolve_write_fault;
 pub use self::mapper::Mapper;
 
 use self::table::{Level4, Table};
 use self::temporary_page::TemporaryPage;
-use memory::{Frame, FrameAllocator, PAGE_SIZE};
-use multiboot2::BootInformation;
+use memory::vma::{self, Permissions, Vma, VmaKind, VmaSet};
+use memory::{Frame, FrameAllocator, GlobalFrameAllocator, PAGE_SIZE};
+use multiboot2::{BootInformation, MemoryAreaIter};
 
+use core::mem;
 use core::ops::{Add, Deref, DerefMut};
 use core::ptr::Unique;
+use spin::MutexGuard;
 use x86_64::instructions::tlb;
 use x86_64::registers::control_regs;
 
+mod cow;
 mod entry;
 mod mapper;
 mod table;
 mod temporary_page;
 
 const ENTRY_COUNT: usize = 512;
+// The P4 entry that points back at the P4 itself. The last entry holds the
+// kernel, which is linked at `KERNEL_OFFSET`.
+pub const RECURSIVE_INDEX: usize = 510;
+// P4 entries below this one make up the user half of an address space, the
+// rest is the kernel half shared by all of them.
+const KERNEL_P4_START: usize = ENTRY_COUNT / 2;
 
 pub type PhysicalAddress = usize;
 pub type VirtualAddress = usize;
@@ -78,6 +89,110 @@ impl Add<usize> for Page {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HugePageSize {
+    TwoMiB,
+    OneGiB,
+}
+
+impl HugePageSize {
+    pub fn frame_count(&self) -> usize {
+        match *self {
+            HugePageSize::TwoMiB => ENTRY_COUNT,
+            HugePageSize::OneGiB => ENTRY_COUNT * ENTRY_COUNT,
+        }
+    }
+}
+
+/// Whether the CPU can map 1GiB pages straight from a P3 entry.
+pub fn supports_1gib_pages() -> bool {
+    use core::arch::x86_64::__cpuid;
+
+    unsafe {
+        __cpuid(0x8000_0000).eax >= 0x8000_0001 && __cpuid(0x8000_0001).edx & (1 << 26) != 0
+    }
+}
+
+/// Maps all physical memory up to the end of the last memory area at
+/// `PHYSICAL_MEMORY_OFFSET`, using the largest pages that fit. The holes
+/// between memory areas hold firmware tables and device memory like the local
+/// APIC, so they are mapped uncached to agree with `map_mmio`. Returns the end
+/// of the mapped memory.
+pub fn map_physical_memory<A>(
+    active_table: &mut ActivePageTable,
+    memory_areas: MemoryAreaIter,
+    allocator: &mut A,
+) -> PhysicalAddress
+where
+    A: FrameAllocator,
+{
+    use core::cmp;
+    use PHYSICAL_MEMORY_OFFSET;
+
+    let end = memory_areas
+        .clone()
+        .map(|area| (area.start_address() + area.size()) as usize)
+        .max()
+        .expect("no memory areas");
+    // how many bytes of `start..end` are part of a memory area
+    let area_bytes = |start: usize, end: usize| -> usize {
+        memory_areas
+            .clone()
+            .map(|area| {
+                let area_start = cmp::max(area.start_address() as usize, start);
+                let area_end = cmp::min((area.start_address() + area.size()) as usize, end);
+                area_end.saturating_sub(area_start)
+            })
+            .sum()
+    };
+    let huge_page_sizes: &[HugePageSize] = if supports_1gib_pages() {
+        &[HugePageSize::OneGiB, HugePageSize::TwoMiB]
+    } else {
+        &[HugePageSize::TwoMiB]
+    };
+
+    let mut address = 0;
+    while address < end {
+        // a huge page must lie entirely inside or outside of the memory areas
+        let huge_page = huge_page_sizes.iter().cloned().find(|size| {
+            let size = size.frame_count() * PAGE_SIZE;
+            let bytes = area_bytes(address, address + size);
+            address % size == 0 && (bytes == 0 || bytes == size)
+        });
+        let size = huge_page.map_or(PAGE_SIZE, |size| size.frame_count() * PAGE_SIZE);
+
+        // memory areas needn't be page aligned, a partially covered page is
+        // still normal memory
+        let flags = if area_bytes(address, address + size) > 0 {
+            WRITABLE | NO_EXECUTE
+        } else {
+            WRITABLE | NO_EXECUTE | NO_CACHE | WRITE_THROUGH
+        };
+
+        let page = Page::containing_address(PHYSICAL_MEMORY_OFFSET + address);
+        let frame = Frame::containing_address(address);
+        match huge_page {
+            Some(huge_page) => active_table.map_to_huge(page, frame, huge_page, flags, allocator),
+            None => active_table.map_to(page, frame, flags, allocator),
+        }
+        address += size;
+    }
+    address
+}
+
+/// Creates a P3 table for every P4 entry of the kernel half. Address spaces
+/// copy the kernel's P4 entries when they are created, so the entries must
+/// never change afterwards or the address spaces would drift apart. This
+/// costs about 1MiB of page tables.
+pub fn preallocate_kernel_tables<A>(active_table: &mut ActivePageTable, allocator: &mut A)
+where
+    A: FrameAllocator,
+{
+    for index in (KERNEL_P4_START..ENTRY_COUNT).filter(|&index| index != RECURSIVE_INDEX) {
+        active_table.p4_mut().next_table_create(index, allocator);
+    }
+}
+
 #[derive(Clone)]
 pub struct PageIter {
     start: Page,
@@ -134,36 +249,102 @@ impl ActivePageTable {
         {
             let backup = Frame::containing_address(control_regs::cr3().0 as usize);
             let p4_table = temporary_page.map_table_frame(backup.clone(), self);
-            self.p4_mut()[511].set(table.p4_frame.clone(), PRESENT | WRITABLE);
+            self.p4_mut()[RECURSIVE_INDEX].set(table.p4_frame.clone(), PRESENT | WRITABLE);
             tlb::flush_all();
             f(self);
-            p4_table[511].set(backup, PRESENT | WRITABLE);
+            p4_table[RECURSIVE_INDEX].set(backup, PRESENT | WRITABLE);
             tlb::flush_all();
         } // inner scope ensures the table variable is dropped before unmapping the temporary page.
         temporary_page.unmap(self);
     }
 
+    /// Makes `new_table` the active address space and returns the previously
+    /// active one. `new_table` is consumed without being dropped, since its
+    /// tables are in use from now on and must not be freed.
     pub fn switch(&mut self, new_table: InactivePageTable) -> InactivePageTable {
+        use core::ptr;
         use x86_64::registers::control_regs;
         use x86_64::PhysicalAddress;
 
-        let old_table = InactivePageTable {
+        let mut old_table = InactivePageTable {
             p4_frame: Frame::containing_address(control_regs::cr3().0 as usize),
+            vmas: VmaSet::new(),
         };
 
         unsafe {
             control_regs::cr3_write(PhysicalAddress(new_table.p4_frame.start_address() as u64));
         }
+        // can't be moved out, `InactivePageTable` implements `Drop`
+        let new_vmas = unsafe { ptr::read(&new_table.vmas) };
+        mem::forget(new_table);
+        old_table.vmas = mem::replace(&mut *vma::ACTIVE.lock(), new_vmas);
         old_table
     }
+
+    /// The memory areas of the user half of this address space.
+    pub fn vmas(&self) -> MutexGuard<'static, VmaSet> {
+        vma::ACTIVE.lock()
+    }
+
+    /// The memory areas of the kernel half, which is the same in every
+    /// address space.
+    pub fn kernel_vmas(&self) -> MutexGuard<'static, VmaSet> {
+        vma::KERNEL.lock()
+    }
+
+    /// Creates a copy of this address space that shares its user pages
+    /// copy-on-write.
+    pub fn clone_cow(&mut self, allocator: &mut GlobalFrameAllocator) -> InactivePageTable {
+        let p4_frame = Frame::containing_address(control_regs::cr3().0 as usize);
+        let clone = InactivePageTable {
+            p4_frame: cow::clone_address_space(&p4_frame, allocator),
+            vmas: self.vmas().clone(),
+        };
+        // our own writable user pages just became read-only
+        tlb::flush_all();
+        clone
+    }
 }
 
+/// An address space that isn't active. Its user half, including the frames
+/// mapped there, is freed when it is dropped.
 pub struct InactivePageTable {
     p4_frame: Frame,
+    vmas: VmaSet,
 }
 
 impl InactivePageTable {
+    /// Creates an address space with an empty user half. The kernel half is
+    /// shared with `active_table`.
     pub fn new(
+        active_table: &mut ActivePageTable,
+        allocator: &mut GlobalFrameAllocator,
+    ) -> InactivePageTable {
+        let p4_frame = allocator.allocate_frame().expect("out of memory");
+        {
+            let active_p4 = active_table.p4();
+            let p4 = unsafe { table::entries(&p4_frame) };
+            for (index, entry) in p4.iter_mut().enumerate() {
+                entry.set_unused();
+                if index < KERNEL_P4_START {
+                    continue;
+                }
+                if let Some(frame) = active_p4[index].pointed_frame() {
+                    entry.set(frame, active_p4[index].flags());
+                }
+            }
+            p4[RECURSIVE_INDEX].set(p4_frame.clone(), PRESENT | WRITABLE);
+        }
+
+        InactivePageTable {
+            p4_frame: p4_frame,
+            vmas: VmaSet::new(),
+        }
+    }
+
+    /// Creates a table with nothing but the recursive entry, for
+    /// `remap_the_kernel` to fill in.
+    fn empty(
         frame: Frame,
         active_table: &mut ActivePageTable,
         temporary_page: &mut TemporaryPage,
@@ -171,12 +352,80 @@ impl InactivePageTable {
         {
             let table = temporary_page.map_table_frame(frame.clone(), active_table);
             table.zero();
-            table[511].set(frame.clone(), PRESENT | WRITABLE);
+            table[RECURSIVE_INDEX].set(frame.clone(), PRESENT | WRITABLE);
         } // inner scope ensures the table variable is dropped before unmapping the temporary page.
         println!("unmapped");
         temporary_page.unmap(active_table);
         println!("unmapped");
-        InactivePageTable { p4_frame: frame }
+
+        InactivePageTable {
+            p4_frame: frame,
+            vmas: VmaSet::new(),
+        }
+    }
+
+    /// Like `ActivePageTable::vmas`.
+    pub fn vmas_mut(&mut self) -> &mut VmaSet {
+        &mut self.vmas
+    }
+
+    /// Like `ActivePageTable::clone_cow`.
+    pub fn clone_cow(&mut self, allocator: &mut GlobalFrameAllocator) -> InactivePageTable {
+        InactivePageTable {
+            p4_frame: cow::clone_address_space(&self.p4_frame, allocator),
+            vmas: self.vmas.clone(),
+        }
+    }
+}
+
+impl Drop for InactivePageTable {
+    fn drop(&mut self) {
+        let allocator = &mut GlobalFrameAllocator;
+        let p4 = unsafe { table::entries(&self.p4_frame) };
+        for entry in p4[..KERNEL_P4_START].iter_mut() {
+            if let Some(frame) = entry.pointed_frame() {
+                free_table(frame, 3, allocator);
+                entry.set_unused();
+            }
+        }
+        allocator.deallocate_frame(self.p4_frame.clone());
+    }
+}
+
+/// Frees the P`level` table in `table_frame`, the tables below it and the
+/// frames they map. Shared frames only lose a reference.
+fn free_table(table_frame: Frame, level: usize, allocator: &mut GlobalFrameAllocator) {
+    {
+        let table = unsafe { table::entries(&table_frame) };
+        for entry in table.iter_mut() {
+            let frame = match entry.pointed_frame() {
+                Some(frame) => frame,
+                None => continue,
+            };
+            if level == 1 {
+                allocator.deallocate_frame(frame);
+            } else {
+                assert!(
+                    !entry.flags().contains(HUGE_PAGE),
+                    "huge pages in the user half can't be freed"
+                );
+                free_table(frame, level - 1, allocator);
+            }
+            entry.set_unused();
+        }
+    }
+    allocator.deallocate_frame(table_frame);
+}
+
+/// The physical address of `address` in the kernel image. Everything but the
+/// boot code is linked `KERNEL_OFFSET` bytes above where it is loaded.
+pub fn kernel_physical_address(address: VirtualAddress) -> PhysicalAddress {
+    use KERNEL_OFFSET;
+
+    if address >= KERNEL_OFFSET {
+        address - KERNEL_OFFSET
+    } else {
+        address
     }
 }
 
@@ -184,14 +433,30 @@ pub fn remap_the_kernel<A>(allocator: &mut A, boot_info: &BootInformation) -> Ac
 where
     A: FrameAllocator,
 {
-    let mut temporary_page = TemporaryPage::new(Page { number: 0xcafebabe }, allocator);
+    use KERNEL_OFFSET;
+
+    // the page right below the kernel, so the low half stays untouched
+    let temporary_page_address = KERNEL_OFFSET - PAGE_SIZE;
+    let mut temporary_page =
+        TemporaryPage::new(Page::containing_address(temporary_page_address), allocator);
 
     let mut active_table = unsafe { ActivePageTable::new() };
     let mut new_table = {
         let frame = allocator.allocate_frame().expect("no more frames");
-        InactivePageTable::new(frame, &mut active_table, &mut temporary_page)
+        InactivePageTable::empty(frame, &mut active_table, &mut temporary_page)
     };
 
+    let mut kernel_vmas = VmaSet::new();
+    // the recursive entry makes every page table show up in the 512GiB
+    // covered by that P4 entry
+    let recursive_start = 0xffff_0000_0000_0000 | (RECURSIVE_INDEX << 39);
+    kernel_vmas.insert(Vma::new(
+        recursive_start,
+        recursive_start + (1 << 39),
+        Permissions::KERNEL_DATA,
+        VmaKind::PageTables,
+    ))
+    .expect("too many memory areas");
     active_table.with(&mut new_table, &mut temporary_page, |mapper| {
         let elf_sections_tag = boot_info
             .elf_sections_tag()
@@ -204,6 +469,14 @@ where
                 // section isn't loaded into memory so we dont need to map it
                 continue;
             }
+            if section.size() == 0 {
+                // nothing to map, and an empty memory area isn't valid
+                continue;
+            }
+            if (section.start_address() as usize) < KERNEL_OFFSET {
+                // the boot code isn't needed once we're in the higher half
+                continue;
+            }
             assert!(
                 section.start_address() as usize % PAGE_SIZE == 0,
                 "sections must be page aligned"
@@ -217,29 +490,60 @@ where
 
             let flags = EntryFlags::from_elf_section_flags(&section);
 
-            let start_frame = Frame::containing_address(section.start_address() as usize);
-            let end_frame = Frame::containing_address((section.end_address() - 1) as usize);
-            for frame in Frame::range_inclusive(start_frame, end_frame) {
-                mapper.identity_map(frame, flags, allocator);
+            let start_page = Page::containing_address(section.start_address() as usize);
+            let end_page = Page::containing_address((section.end_address() - 1) as usize);
+            for page in Page::range_inclusive(start_page, end_page) {
+                let physical_address = kernel_physical_address(page.start_address());
+                let frame = Frame::containing_address(physical_address);
+                mapper.map_to(page, frame, flags, allocator);
             }
+            kernel_vmas.insert(Vma::new(
+                start_page.start_address(),
+                end_page.start_address() + PAGE_SIZE,
+                Permissions::from_entry_flags(flags),
+                VmaKind::Kernel,
+            ))
+            .expect("too many memory areas");
         }
 
-        let vga_buffer_frame = Frame::containing_address(0xb8000);
-        mapper.identity_map(vga_buffer_frame, WRITABLE, allocator);
-
-        let multiboot_start = Frame::containing_address(boot_info.start_address());
-        let multiboot_end = Frame::containing_address(boot_info.end_address() - 1);
-        for frame in Frame::range_inclusive(multiboot_start, multiboot_end) {
-            mapper.identity_map(frame, PRESENT, allocator);
+        // the VGA buffer and the multiboot information stay where the boot
+        // page tables put them, at their physical address plus KERNEL_OFFSET
+        let vga_buffer_page = Page::containing_address(KERNEL_OFFSET + 0xb8000);
+        mapper.map_to(vga_buffer_page, Frame::containing_address(0xb8000), WRITABLE, allocator);
+        kernel_vmas.insert(Vma::new(
+            vga_buffer_page.start_address(),
+            vga_buffer_page.start_address() + PAGE_SIZE,
+            Permissions::from_entry_flags(PRESENT | WRITABLE),
+            VmaKind::Mmio,
+        ))
+        .expect("too many memory areas");
+
+        let multiboot_start = Page::containing_address(boot_info.start_address());
+        let multiboot_end = Page::containing_address(boot_info.end_address() - 1);
+        for page in Page::range_inclusive(multiboot_start, multiboot_end) {
+            let frame = Frame::containing_address(page.start_address() - KERNEL_OFFSET);
+            mapper.map_to(page, frame, PRESENT, allocator);
         }
+        kernel_vmas.insert(Vma::new(
+            multiboot_start.start_address(),
+            multiboot_end.start_address() + PAGE_SIZE,
+            Permissions::from_entry_flags(PRESENT),
+            VmaKind::Reserved,
+        ))
+        .expect("too many memory areas");
     });
+    *vma::KERNEL.lock() = kernel_vmas;
 
     let old_table = active_table.switch(new_table);
     println!("New table!");
 
-    let old_p4_page = Page::containing_address(old_table.p4_frame.start_address());
+    let old_p4_page =
+        Page::containing_address(KERNEL_OFFSET + old_table.p4_frame.start_address());
     active_table.unmap(old_p4_page, allocator);
     println!("guard page at {:#x}", old_p4_page.start_address());
+    // the boot page tables are part of the kernel image, so they must not be
+    // freed like those of a user address space
+    mem::forget(old_table);
 
     active_table
 }
@@ -3,9 +3,19 @@ use core::ops::{Index, IndexMut};
 
 use memory::paging::entry::*;
 use memory::paging::ENTRY_COUNT;
-use memory::FrameAllocator;
+use memory::{Frame, FrameAllocator};
 
-pub const P4: *mut Table<Level4> = 0xffffffff_fffff000 as *mut _;
+// The address with every table index set to `RECURSIVE_INDEX`.
+pub const P4: *mut Table<Level4> = 0xffffff7f_bfdfe000 as *mut _;
+
+/// The entries of the page table in `frame`, accessed through the direct map
+/// of physical memory, so the table doesn't have to be part of the active
+/// address space.
+pub unsafe fn entries(frame: &Frame) -> &'static mut [Entry; ENTRY_COUNT] {
+    use PHYSICAL_MEMORY_OFFSET;
+
+    &mut *((PHYSICAL_MEMORY_OFFSET + frame.start_address()) as *mut _)
+}
 
 pub struct Table<L: TableLevel> {
     entries: [Entry; ENTRY_COUNT],
@@ -41,6 +51,10 @@ where
             entry.set_unused();
         }
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.iter().all(|entry| entry.is_unused())
+    }
 }
 
 impl<L> Table<L>
@@ -61,7 +75,10 @@ where
         let entry_flags = self[index].flags();
         if entry_flags.contains(PRESENT) && !entry_flags.contains(HUGE_PAGE) {
             let table_address = self as *const _ as usize;
-            Some((table_address << 9) | (index << 12))
+            // shifting out the recursive index leaves the upper 16 bits wrong,
+            // so sign extend bit 47 again
+            let address = (table_address << 9) | (index << 12);
+            Some(((address << 16) as isize >> 16) as usize)
         } else {
             None
         }
@@ -78,7 +95,7 @@ where
         if self.next_table(index).is_none() {
             assert!(
                 !self.entries[index].flags().contains(HUGE_PAGE),
-                "Mapping huge pages is unsupported in huOS"
+                "a huge page is mapped here, split it first"
             );
             let frame = allocator.allocate_frame().expect("no frames available");
             self.entries[index].set(frame, PRESENT | WRITABLE);
@@ -86,6 +103,28 @@ where
         }
         self.next_table_mut(index).unwrap()
     }
+
+    /// Frees the table at `index` if none of its entries are in use, returning
+    /// whether it was freed.
+    pub fn free_next_table_if_empty<A>(&mut self, index: usize, allocator: &mut A) -> bool
+    where
+        A: FrameAllocator,
+    {
+        use x86_64::instructions::tlb;
+        use x86_64::VirtualAddress;
+
+        let table_address = match self.next_table(index) {
+            Some(table) if table.is_empty() => table as *const _ as usize,
+            _ => return false,
+        };
+
+        let frame = self[index].pointed_frame().unwrap();
+        self[index].set_unused();
+        // the recursive mapping of the freed table must not linger in the TLB
+        tlb::flush(VirtualAddress(table_address));
+        allocator.deallocate_frame(frame);
+        true
+    }
 }
 
 pub trait TableLevel {}
@@ -38,7 +38,8 @@ impl TemporaryPage {
     }
 
     pub fn unmap(&mut self, active_table: &mut ActivePageTable) {
-        active_table.unmap(self.page, &mut self.allocator)
+        // the mapped frame belongs to the caller, only the page tables are freed
+        active_table.unmap(self.page, &mut self.allocator);
     }
 }
 
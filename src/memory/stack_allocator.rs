@@ -1,13 +1,69 @@
 use memory::paging::{self, ActivePageTable, Page, PageIter};
+use memory::vma::{Permissions, Vma, VmaKind};
 use memory::{FrameAllocator, PAGE_SIZE};
+use spin::Mutex;
+
+// More than fit into the stack area, even with single page stacks.
+const MAX_STACKS: usize = 64;
+// Free slots are merged with their free neighbours, so there is at least one
+// stack in use between any two of them.
+const FREE_SLOT_COUNT: usize = MAX_STACKS + 1;
+
+/// The bounds of every stack in use, indexed by stack id. Lives outside the
+/// `StackAllocator` so the page fault handler can look up which stack's guard
+/// page was hit.
+static STACKS: Mutex<[Option<StackBounds>; MAX_STACKS]> = Mutex::new([None; MAX_STACKS]);
+
+#[derive(Debug, Clone, Copy)]
+pub struct StackBounds {
+    pub id: usize,
+    pub bottom: usize,
+    pub top: usize,
+}
+
+impl StackBounds {
+    /// The start address of the unmapped page directly below the stack.
+    pub fn guard_page(&self) -> usize {
+        self.bottom - PAGE_SIZE
+    }
+}
+
+/// Returns the stack whose guard page contains `address`, if any.
+///
+/// Doesn't wait for the stack list if it is locked, since this is called from
+/// the page fault handler, which might have interrupted its owner.
+pub fn overflowed_stack(address: usize) -> Option<StackBounds> {
+    let stacks = STACKS.try_lock()?;
+    stacks
+        .iter()
+        .filter_map(|stack| *stack)
+        .find(|stack| address >= stack.guard_page() && address < stack.bottom)
+}
+
+/// A freed stack's guard page followed by the pages the stack used.
+#[derive(Clone, Copy)]
+struct StackSlot {
+    guard_page: Page,
+    size_in_pages: usize,
+}
+
+impl StackSlot {
+    fn last_page(&self) -> Page {
+        self.guard_page + self.size_in_pages
+    }
+}
 
 pub struct StackAllocator {
     range: PageIter,
+    free_slots: [Option<StackSlot>; FREE_SLOT_COUNT],
 }
 
 impl StackAllocator {
     pub fn new(page_range: PageIter) -> StackAllocator {
-        StackAllocator { range: page_range }
+        StackAllocator {
+            range: page_range,
+            free_slots: [None; FREE_SLOT_COUNT],
+        }
     }
 
     pub fn alloc_stack<FA: FrameAllocator>(
@@ -20,6 +76,72 @@ impl StackAllocator {
             return None;
         }
 
+        let (start, end) = match self.take_free_slot(size_in_pages) {
+            Some(guard_page) => (guard_page + 1, guard_page + size_in_pages),
+            None => self.take_from_range(size_in_pages)?,
+        };
+
+        let bottom = start.start_address();
+        let top_of_stack = end.start_address() + PAGE_SIZE;
+        let guard_vma = Vma::new(bottom - PAGE_SIZE, bottom, Permissions::NONE, VmaKind::GuardPage);
+        let stack_vma = Vma::new(bottom, top_of_stack, Permissions::KERNEL_DATA, VmaKind::Stack);
+        let inserted = {
+            let mut vmas = active_table.kernel_vmas();
+            match vmas.insert(guard_vma) {
+                Ok(()) => {
+                    let inserted = vmas.insert(stack_vma);
+                    if inserted.is_err() {
+                        vmas.remove(guard_vma.start);
+                    }
+                    inserted
+                }
+                Err(full) => Err(full),
+            }
+        };
+        if inserted.is_err() {
+            self.add_free_slot(StackSlot {
+                guard_page: Page::containing_address(guard_vma.start),
+                size_in_pages: size_in_pages,
+            });
+            return None;
+        }
+
+        for page in Page::range_inclusive(start, end) {
+            active_table.map(page, paging::WRITABLE, frame_allocator);
+        }
+        Some(Stack::new(top_of_stack, start.start_address()))
+    }
+
+    /// Unmaps the stack, returning its frames to `frame_allocator`, and keeps
+    /// its pages around for a later `alloc_stack`.
+    pub fn free_stack<FA: FrameAllocator>(
+        &mut self,
+        stack: Stack,
+        active_table: &mut ActivePageTable,
+        frame_allocator: &mut FA,
+    ) {
+        STACKS.lock()[stack.id] = None;
+        {
+            let mut vmas = active_table.kernel_vmas();
+            vmas.remove(stack.bottom() - PAGE_SIZE);
+            vmas.remove(stack.bottom());
+        }
+
+        let start = Page::containing_address(stack.bottom());
+        let end = Page::containing_address(stack.top() - 1);
+        for page in Page::range_inclusive(start, end) {
+            active_table.unmap_and_free(page, frame_allocator);
+        }
+
+        self.add_free_slot(StackSlot {
+            guard_page: Page::containing_address(stack.bottom() - PAGE_SIZE),
+            size_in_pages: stack.size_in_pages(),
+        });
+    }
+
+    /// Takes a guard page and `size_in_pages` pages from the part of the range
+    /// that has never been used, if there is enough of it left.
+    fn take_from_range(&mut self, size_in_pages: usize) -> Option<(Page, Page)> {
         let mut range = self.range.clone();
 
         let guard_page = range.next();
@@ -33,21 +155,65 @@ impl StackAllocator {
         match (guard_page, stack_start, stack_end) {
             (Some(_), Some(start), Some(end)) => {
                 self.range = range;
+                Some((start, end))
+            }
+            _ => None,
+        }
+    }
 
-                for page in Page::range_inclusive(start, end) {
-                    active_table.map(page, paging::WRITABLE, frame_allocator);
-                }
+    /// Finds the smallest free slot that fits `size_in_pages`, returning its
+    /// guard page. Whatever the stack doesn't use stays free.
+    fn take_free_slot(&mut self, size_in_pages: usize) -> Option<Page> {
+        let index = self.free_slots
+            .iter()
+            .enumerate()
+            .filter_map(|(i, slot)| slot.map(|slot| (i, slot.size_in_pages)))
+            .filter(|&(_, size)| size >= size_in_pages)
+            .min_by_key(|&(_, size)| size)
+            .map(|(i, _)| i)?;
+        let slot = self.free_slots[index].take().unwrap();
 
-                let top_of_stack = end.start_address() + PAGE_SIZE;
-                Some(Stack::new(top_of_stack, start.start_address()))
+        // the leftover needs a guard page of its own. Kept even if that is all
+        // there is, so it can merge with a neighbour once that is freed
+        if slot.size_in_pages > size_in_pages {
+            self.free_slots[index] = Some(StackSlot {
+                guard_page: slot.guard_page + size_in_pages + 1,
+                size_in_pages: slot.size_in_pages - size_in_pages - 1,
+            });
+        }
+        Some(slot.guard_page)
+    }
+
+    fn add_free_slot(&mut self, mut slot: StackSlot) {
+        // merge with free neighbours, the guard page in between becomes usable
+        for existing in self.free_slots.iter_mut() {
+            let neighbour = match *existing {
+                Some(neighbour) => neighbour,
+                None => continue,
+            };
+            if neighbour.last_page() + 1 == slot.guard_page {
+                slot = StackSlot {
+                    guard_page: neighbour.guard_page,
+                    size_in_pages: neighbour.size_in_pages + 1 + slot.size_in_pages,
+                };
+                *existing = None;
+            } else if slot.last_page() + 1 == neighbour.guard_page {
+                slot.size_in_pages += 1 + neighbour.size_in_pages;
+                *existing = None;
             }
-            _ => None,
         }
+
+        let free = self.free_slots
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .expect("free stack slots can't run out, see `FREE_SLOT_COUNT`");
+        *free = Some(slot);
     }
 }
 
 #[derive(Debug)]
 pub struct Stack {
+    id: usize,
     top: usize,
     bottom: usize,
 }
@@ -55,12 +221,29 @@ pub struct Stack {
 impl Stack {
     fn new(top: usize, bottom: usize) -> Stack {
         assert!(top > bottom);
+
+        let mut stacks = STACKS.lock();
+        let id = stacks
+            .iter()
+            .position(|stack| stack.is_none())
+            .expect("too many stacks");
+        stacks[id] = Some(StackBounds {
+            id: id,
+            bottom: bottom,
+            top: top,
+        });
+
         Stack {
+            id: id,
             top: top,
             bottom: bottom,
         }
     }
 
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
     pub fn top(&self) -> usize {
         self.top
     }
@@ -68,4 +251,8 @@ impl Stack {
     pub fn bottom(&self) -> usize {
         self.bottom
     }
+
+    pub fn size_in_pages(&self) -> usize {
+        (self.top - self.bottom) / PAGE_SIZE
+    }
 }
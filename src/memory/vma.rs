@@ -0,0 +1,386 @@
+use core::fmt;
+use core::slice;
+use memory::paging::{self, EntryFlags, VirtualAddress};
+use memory::PAGE_SIZE;
+use spin::Mutex;
+
+// Enough for the kernel image, the fixed regions and a few dozen stacks and
+// MMIO regions.
+const MAX_AREAS: usize = 64;
+
+/// The areas of the kernel half, which every address space shares. Statics so
+/// the page fault handler can get at them.
+pub static KERNEL: Mutex<VmaSet> = Mutex::new(VmaSet::new());
+/// The areas of the user half of the active address space.
+/// `ActivePageTable::switch` swaps them with those of the table it switches to.
+pub static ACTIVE: Mutex<VmaSet> = Mutex::new(VmaSet::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaKind {
+    Kernel,
+    PageTables,
+    PhysicalMemory,
+    Heap,
+    Stack,
+    GuardPage,
+    Mmio,
+    Reserved,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Permissions {
+    pub readable: bool,
+    pub writable: bool,
+    pub executable: bool,
+    pub user: bool,
+}
+
+impl Permissions {
+    /// No access at all, e.g. for guard pages.
+    pub const NONE: Permissions = Permissions {
+        readable: false,
+        writable: false,
+        executable: false,
+        user: false,
+    };
+
+    /// Readable and writable by the kernel only.
+    pub const KERNEL_DATA: Permissions = Permissions {
+        readable: true,
+        writable: true,
+        executable: false,
+        user: false,
+    };
+
+    pub fn from_entry_flags(flags: EntryFlags) -> Permissions {
+        Permissions {
+            readable: flags.contains(paging::PRESENT),
+            writable: flags.contains(paging::WRITABLE),
+            executable: !flags.contains(paging::NO_EXECUTE),
+            user: flags.contains(paging::USER_ACCESSIBLE),
+        }
+    }
+
+    pub fn entry_flags(&self) -> EntryFlags {
+        let mut flags = EntryFlags::empty();
+        if self.readable {
+            flags = flags | paging::PRESENT;
+        }
+        if self.writable {
+            flags = flags | paging::WRITABLE;
+        }
+        if !self.executable {
+            flags = flags | paging::NO_EXECUTE;
+        }
+        if self.user {
+            flags = flags | paging::USER_ACCESSIBLE;
+        }
+        flags
+    }
+}
+
+impl fmt::Display for Permissions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |set, c| if set { c } else { '-' };
+        write!(
+            f,
+            "{}{}{}{}",
+            flag(self.readable, 'r'),
+            flag(self.writable, 'w'),
+            flag(self.executable, 'x'),
+            flag(self.user, 'u')
+        )
+    }
+}
+
+/// A virtual memory area, `start..end` with the same permissions and backing
+/// throughout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vma {
+    pub start: VirtualAddress,
+    pub end: VirtualAddress,
+    pub permissions: Permissions,
+    pub kind: VmaKind,
+}
+
+impl Vma {
+    pub fn new(
+        start: VirtualAddress,
+        end: VirtualAddress,
+        permissions: Permissions,
+        kind: VmaKind,
+    ) -> Vma {
+        assert!(start < end, "empty memory area at {:#x}", start);
+        Vma {
+            start: start,
+            end: end,
+            permissions: permissions,
+            kind: kind,
+        }
+    }
+
+    pub fn contains(&self, address: VirtualAddress) -> bool {
+        address >= self.start && address < self.end
+    }
+
+    pub fn size(&self) -> usize {
+        self.end - self.start
+    }
+}
+
+impl fmt::Display for Vma {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:016x}-{:016x} {} {:?}",
+            self.start, self.end, self.permissions, self.kind
+        )
+    }
+}
+
+/// Returned by `VmaSet::insert` when there is no room for another area.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmaSetFull;
+
+const UNUSED: Vma = Vma {
+    start: 0,
+    end: 0,
+    permissions: Permissions::NONE,
+    kind: VmaKind::Reserved,
+};
+
+/// The areas of one half of an address space, sorted by address. A fixed size array
+/// rather than a tree, since areas are added before the heap exists and
+/// looked up from the page fault handler, which must not allocate.
+pub struct VmaSet {
+    areas: [Vma; MAX_AREAS],
+    len: usize,
+}
+
+impl VmaSet {
+    pub const fn new() -> VmaSet {
+        VmaSet {
+            areas: [UNUSED; MAX_AREAS],
+            len: 0,
+        }
+    }
+
+    /// Adds `vma`, which must not overlap any area already in the set.
+    pub fn insert(&mut self, vma: Vma) -> Result<(), VmaSetFull> {
+        let index = match self.areas().binary_search_by_key(&vma.start, |area| area.start) {
+            Ok(_) => panic!("memory area {} overlaps {}", vma, self.find(vma.start).unwrap()),
+            Err(index) => index,
+        };
+        if index > 0 && self.areas[index - 1].end > vma.start {
+            panic!("memory area {} overlaps {}", vma, self.areas[index - 1]);
+        }
+        if index < self.len && self.areas[index].start < vma.end {
+            panic!("memory area {} overlaps {}", vma, self.areas[index]);
+        }
+        if self.len == MAX_AREAS {
+            return Err(VmaSetFull);
+        }
+
+        let mut i = self.len;
+        while i > index {
+            self.areas[i] = self.areas[i - 1];
+            i -= 1;
+        }
+        self.areas[index] = vma;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Removes the area starting at `start`.
+    pub fn remove(&mut self, start: VirtualAddress) -> Option<Vma> {
+        let index = self.areas()
+            .binary_search_by_key(&start, |area| area.start)
+            .ok()?;
+        let vma = self.areas[index];
+
+        for i in index..self.len - 1 {
+            self.areas[i] = self.areas[i + 1];
+        }
+        self.len -= 1;
+        Some(vma)
+    }
+
+    /// The area containing `address`, if any.
+    pub fn find(&self, address: VirtualAddress) -> Option<&Vma> {
+        let index = match self.areas().binary_search_by_key(&address, |area| area.start) {
+            Ok(index) => index,
+            Err(0) => return None,
+            Err(index) => index - 1,
+        };
+        Some(&self.areas[index]).filter(|area| area.contains(address))
+    }
+
+    /// The lowest address in `start..end` with `size` unused bytes above it,
+    /// e.g. to place an mmap. All three must be page aligned.
+    pub fn find_free(
+        &self,
+        size: usize,
+        start: VirtualAddress,
+        end: VirtualAddress,
+    ) -> Option<VirtualAddress> {
+        assert!(
+            start % PAGE_SIZE == 0 && end % PAGE_SIZE == 0 && size % PAGE_SIZE == 0,
+            "free areas are searched page wise"
+        );
+
+        let mut candidate = start;
+        for area in self.iter().filter(|area| area.end > start && area.start < end) {
+            if area.start >= candidate && area.start - candidate >= size {
+                break;
+            }
+            if area.end > candidate {
+                candidate = area.end;
+            }
+        }
+        if candidate <= end && end - candidate >= size {
+            Some(candidate)
+        } else {
+            None
+        }
+    }
+
+    pub fn iter(&self) -> slice::Iter<Vma> {
+        self.areas().iter()
+    }
+
+    fn areas(&self) -> &[Vma] {
+        &self.areas[..self.len]
+    }
+}
+
+impl Clone for VmaSet {
+    fn clone(&self) -> VmaSet {
+        VmaSet {
+            areas: self.areas,
+            len: self.len,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Permissions, Vma, VmaKind, VmaSet, VmaSetFull, MAX_AREAS};
+    use memory::PAGE_SIZE;
+
+    fn vma(start: usize, end: usize) -> Vma {
+        Vma::new(start, end, Permissions::KERNEL_DATA, VmaKind::Reserved)
+    }
+
+    fn starts(vmas: &VmaSet) -> [usize; 3] {
+        let mut starts = [0; 3];
+        for (start, vma) in starts.iter_mut().zip(vmas.iter()) {
+            *start = vma.start;
+        }
+        starts
+    }
+
+    #[test]
+    fn insert_keeps_the_areas_sorted() {
+        let mut vmas = VmaSet::new();
+        vmas.insert(vma(0x3000, 0x4000)).unwrap();
+        vmas.insert(vma(0x1000, 0x2000)).unwrap();
+        vmas.insert(vma(0x5000, 0x6000)).unwrap();
+        assert_eq!(starts(&vmas), [0x1000, 0x3000, 0x5000]);
+    }
+
+    #[test]
+    fn adjacent_areas_dont_overlap() {
+        let mut vmas = VmaSet::new();
+        vmas.insert(vma(0x2000, 0x3000)).unwrap();
+        vmas.insert(vma(0x1000, 0x2000)).unwrap();
+        vmas.insert(vma(0x3000, 0x4000)).unwrap();
+        assert_eq!(vmas.find(0x1fff), Some(&vma(0x1000, 0x2000)));
+        assert_eq!(vmas.find(0x2000), Some(&vma(0x2000, 0x3000)));
+        assert_eq!(vmas.find(0x3fff), Some(&vma(0x3000, 0x4000)));
+    }
+
+    #[test]
+    fn find_outside_of_any_area() {
+        let mut vmas = VmaSet::new();
+        assert_eq!(vmas.find(0x1000), None);
+        vmas.insert(vma(0x2000, 0x3000)).unwrap();
+        vmas.insert(vma(0x5000, 0x6000)).unwrap();
+        assert_eq!(vmas.find(0x1fff), None);
+        assert_eq!(vmas.find(0x3000), None);
+        assert_eq!(vmas.find(0x6000), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "overlaps")]
+    fn same_start() {
+        let mut vmas = VmaSet::new();
+        vmas.insert(vma(0x1000, 0x3000)).unwrap();
+        let _ = vmas.insert(vma(0x1000, 0x2000));
+    }
+
+    #[test]
+    #[should_panic(expected = "overlaps")]
+    fn overlaps_the_area_below() {
+        let mut vmas = VmaSet::new();
+        vmas.insert(vma(0x1000, 0x3000)).unwrap();
+        let _ = vmas.insert(vma(0x2000, 0x4000));
+    }
+
+    #[test]
+    #[should_panic(expected = "overlaps")]
+    fn overlaps_the_area_above() {
+        let mut vmas = VmaSet::new();
+        vmas.insert(vma(0x2000, 0x4000)).unwrap();
+        let _ = vmas.insert(vma(0x1000, 0x3000));
+    }
+
+    #[test]
+    #[should_panic(expected = "overlaps")]
+    fn contains_another_area() {
+        let mut vmas = VmaSet::new();
+        vmas.insert(vma(0x2000, 0x3000)).unwrap();
+        let _ = vmas.insert(vma(0x1000, 0x4000));
+    }
+
+    #[test]
+    fn remove() {
+        let mut vmas = VmaSet::new();
+        vmas.insert(vma(0x1000, 0x2000)).unwrap();
+        vmas.insert(vma(0x3000, 0x4000)).unwrap();
+        vmas.insert(vma(0x5000, 0x6000)).unwrap();
+
+        assert_eq!(vmas.remove(0x3800), None);
+        assert_eq!(vmas.remove(0x3000), Some(vma(0x3000, 0x4000)));
+        assert_eq!(vmas.find(0x3000), None);
+        assert_eq!(starts(&vmas), [0x1000, 0x5000, 0]);
+
+        // the area is free to be used again
+        vmas.insert(vma(0x2000, 0x5000)).unwrap();
+        assert_eq!(starts(&vmas), [0x1000, 0x2000, 0x5000]);
+    }
+
+    #[test]
+    fn full_set() {
+        let mut vmas = VmaSet::new();
+        for i in 0..MAX_AREAS {
+            vmas.insert(vma((i + 1) * PAGE_SIZE, (i + 2) * PAGE_SIZE))
+                .unwrap();
+        }
+        let last = (MAX_AREAS + 1) * PAGE_SIZE;
+        assert_eq!(vmas.insert(vma(last, last + PAGE_SIZE)), Err(VmaSetFull));
+
+        vmas.remove(PAGE_SIZE);
+        vmas.insert(vma(last, last + PAGE_SIZE)).unwrap();
+    }
+
+    #[test]
+    fn find_free() {
+        let mut vmas = VmaSet::new();
+        vmas.insert(vma(0x1000, 0x2000)).unwrap();
+        vmas.insert(vma(0x3000, 0x4000)).unwrap();
+        assert_eq!(vmas.find_free(0x1000, 0x1000, 0x6000), Some(0x2000));
+        assert_eq!(vmas.find_free(0x2000, 0x1000, 0x6000), Some(0x4000));
+        assert_eq!(vmas.find_free(0x3000, 0x1000, 0x6000), None);
+        assert_eq!(vmas.find_free(0x1000, 0x0, 0x1000), Some(0x0));
+    }
+}
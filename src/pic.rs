@@ -6,13 +6,25 @@ const CMD_INIT: u8 = 0x11;
 // Command sent to acknowledge an interrupt.
 const CMD_END_OF_INTERRUPT: u8 = 0x20;
 
+// Commands sent to make the next read of the command port return the IRR or
+// the ISR.
+const CMD_READ_IRR: u8 = 0x0a;
+const CMD_READ_ISR: u8 = 0x0b;
+
 // The mode in which we want to run our PICs.
 const MODE_8086: u8 = 0x01;
 
+// The lowest priority line of a PIC, which it raises for spurious interrupts.
+const SPURIOUS_LINE: u8 = 7;
+
+// The line of the master that the slave is connected to.
+const CASCADE_IRQ: u8 = 2;
+
 struct Pic {
     offset: u8,
     command: UnsafePort<u8>,
     data: UnsafePort<u8>,
+    spurious_count: usize,
 }
 
 impl Pic {
@@ -23,6 +35,19 @@ impl Pic {
     unsafe fn end_of_interrupt(&mut self) {
         self.command.write(CMD_END_OF_INTERRUPT);
     }
+
+    /// The interrupt request register, one bit per line that is waiting to be
+    /// handled.
+    unsafe fn requests(&mut self) -> u8 {
+        self.command.write(CMD_READ_IRR);
+        self.command.read()
+    }
+
+    /// The in-service register, one bit per line that is being handled.
+    unsafe fn in_service(&mut self) -> u8 {
+        self.command.write(CMD_READ_ISR);
+        self.command.read()
+    }
 }
 
 pub struct ChainedPics {
@@ -37,11 +62,13 @@ impl ChainedPics {
                     offset: offset1,
                     command: UnsafePort::new(0x20),
                     data: UnsafePort::new(0x21),
+                    spurious_count: 0,
                 },
                 Pic {
                     offset: offset2,
                     command: UnsafePort::new(0xA0),
                     data: UnsafePort::new(0xA1),
+                    spurious_count: 0,
                 },
             ],
         }
@@ -55,9 +82,6 @@ impl ChainedPics {
                                                        // without a clock.
         let mut wait = || wait_port.write(0); // Wait closure.
 
-        let saved_mask_1 = self.pics[0].data.read(); // Save the masks so we don't have to
-        let saved_mask_2 = self.pics[1].data.read(); // guess sensible values.
-
         self.pics[0].command.write(CMD_INIT); // Write the init command to each PIC.
         wait(); // Wait in between to allow the message
         self.pics[1].command.write(CMD_INIT); // time to be read.
@@ -77,15 +101,92 @@ impl ChainedPics {
         self.pics[1].data.write(MODE_8086);
         wait();
 
-        println!("{:b}\n{:b}\n", saved_mask_1, saved_mask_2);
-        self.pics[0].data.write(saved_mask_1);
-        self.pics[1].data.write(saved_mask_2);
+        // lines stay masked until something handles them
+        self.set_masks(!0);
+    }
+
+    /// Moves the interrupts of the two PICs to the vectors starting at
+    /// `offset1` and `offset2`. Like `init`, this masks every line.
+    pub unsafe fn remap(&mut self, offset1: u8, offset2: u8) {
+        self.pics[0].offset = offset1;
+        self.pics[1].offset = offset2;
+        self.init();
+    }
+
+    /// Stops `irq` from raising interrupts.
+    pub unsafe fn mask(&mut self, irq: u8) {
+        let masks = self.masks();
+        self.set_masks(masks | 1 << irq);
+    }
+
+    /// Lets `irq` raise interrupts again.
+    pub unsafe fn unmask(&mut self, irq: u8) {
+        let masks = self.masks();
+        self.set_masks(masks & !(1 << irq));
+    }
+
+    /// Sets the masks of all 16 lines, IRQ 0 in the lowest bit. A set bit masks
+    /// the line. The cascade line is left unmasked while any line of the slave
+    /// is.
+    pub unsafe fn set_masks(&mut self, masks: u16) {
+        let slave_masks = (masks >> 8) as u8;
+        let mut master_masks = masks as u8 | 1 << CASCADE_IRQ;
+        if slave_masks != !0 {
+            master_masks &= !(1 << CASCADE_IRQ);
+        }
+        self.pics[0].data.write(master_masks);
+        self.pics[1].data.write(slave_masks);
+    }
+
+    /// The masks of all 16 lines, see `set_masks`. IRQ 2 always reads as
+    /// masked, since it only carries the interrupts of the slave.
+    pub unsafe fn masks(&mut self) -> u16 {
+        let master_masks = self.pics[0].data.read() | 1 << CASCADE_IRQ;
+        u16::from(self.pics[1].data.read()) << 8 | u16::from(master_masks)
+    }
+
+    /// The lines with an interrupt waiting to be handled, IRQ 0 in the lowest
+    /// bit.
+    pub unsafe fn interrupt_requests(&mut self) -> u16 {
+        u16::from(self.pics[1].requests()) << 8 | u16::from(self.pics[0].requests())
+    }
+
+    /// The lines whose interrupt is being handled, IRQ 0 in the lowest bit.
+    pub unsafe fn interrupts_in_service(&mut self) -> u16 {
+        u16::from(self.pics[1].in_service()) << 8 | u16::from(self.pics[0].in_service())
     }
 
     pub fn handles_interrupt(&self, interrupt_id: u8) -> bool {
         self.pics.iter().any(|p| p.handles_interrupt(interrupt_id))
     }
 
+    /// Whether `interrupt_id` is a spurious interrupt, which a PIC raises on
+    /// its lowest priority line when an IRQ goes away before the CPU
+    /// acknowledges it. Spurious interrupts must not get an end of interrupt,
+    /// except from the master for one of the slave, since the master saw a
+    /// real IRQ 2.
+    pub unsafe fn is_spurious(&mut self, interrupt_id: u8) -> bool {
+        for i in 0..2 {
+            if interrupt_id != self.pics[i].offset + SPURIOUS_LINE {
+                continue;
+            }
+            if self.pics[i].in_service() & (1 << SPURIOUS_LINE) != 0 {
+                return false;
+            }
+            self.pics[i].spurious_count += 1;
+            if i == 1 {
+                self.pics[0].end_of_interrupt();
+            }
+            return true;
+        }
+        false
+    }
+
+    /// How many spurious interrupts the master and the slave raised.
+    pub fn spurious_counts(&self) -> [usize; 2] {
+        [self.pics[0].spurious_count, self.pics[1].spurious_count]
+    }
+
     pub unsafe fn notify_end_of_interrupt(&mut self, interrupt_id: u8) {
         if self.handles_interrupt(interrupt_id) {
             if self.pics[1].handles_interrupt(interrupt_id) {
@@ -127,7 +127,7 @@ impl fmt::Write for Writer {
 pub static WRITER: Mutex<Writer> = Mutex::new(Writer {
     column_position: 0,
     colour_code: ColourCode::new(Colour::LightGreen, Colour::Black),
-    buffer: unsafe { Unique::new_unchecked(0xb8000 as *mut _) },
+    buffer: unsafe { Unique::new_unchecked((::KERNEL_OFFSET + 0xb8000) as *mut _) },
 });
 
 macro_rules! print {